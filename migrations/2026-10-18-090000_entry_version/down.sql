@@ -0,0 +1 @@
+ALTER TABLE user_storage DROP COLUMN IF EXISTS version;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS user_storage_expires_at_idx;
+
+ALTER TABLE user_storage DROP COLUMN IF EXISTS expires_at;
@@ -0,0 +1,3 @@
+ALTER TABLE user_storage ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS user_storage_expires_at_idx ON user_storage (expires_at) WHERE expires_at IS NOT NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS user_storage_changes (
+    id BIGSERIAL PRIMARY KEY,
+    user_addr TEXT NOT NULL,
+    key TEXT NOT NULL,
+    entry JSONB,
+    version BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS user_storage_changes_user_addr_id_idx ON user_storage_changes (user_addr, id);
+CREATE INDEX IF NOT EXISTS user_storage_changes_created_at_idx ON user_storage_changes (created_at);
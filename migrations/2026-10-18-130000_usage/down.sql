@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS user_storage_usage_trigger ON user_storage;
+DROP FUNCTION IF EXISTS user_storage_track_usage();
+DROP FUNCTION IF EXISTS user_storage_entry_size(user_storage);
+DROP TABLE IF EXISTS user_storage_usage;
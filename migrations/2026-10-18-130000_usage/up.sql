@@ -0,0 +1,47 @@
+CREATE TABLE IF NOT EXISTS user_storage_usage (
+    user_addr TEXT PRIMARY KEY,
+    entry_count BIGINT NOT NULL DEFAULT 0,
+    total_bytes BIGINT NOT NULL DEFAULT 0
+);
+
+-- the key plus the value in its textual form, a fixed size for integers and booleans
+CREATE OR REPLACE FUNCTION user_storage_entry_size(entry user_storage) RETURNS BIGINT AS $$
+    SELECT octet_length(entry.key) + COALESCE(
+        octet_length(entry.entry_value_binary),
+        octet_length(entry.entry_value_string),
+        octet_length(entry.entry_value_json::TEXT),
+        CASE WHEN entry.entry_value_integer IS NOT NULL THEN 8 END,
+        CASE WHEN entry.entry_value_boolean IS NOT NULL THEN 1 END,
+        0
+    )::BIGINT
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION user_storage_track_usage() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE user_storage_usage
+        SET entry_count = entry_count - 1,
+            total_bytes = total_bytes - user_storage_entry_size(OLD)
+        WHERE user_addr = OLD.user_addr;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        INSERT INTO user_storage_usage (user_addr, entry_count, total_bytes)
+        VALUES (NEW.user_addr, 1, user_storage_entry_size(NEW))
+        ON CONFLICT (user_addr) DO UPDATE
+        SET entry_count = user_storage_usage.entry_count + 1,
+            total_bytes = user_storage_usage.total_bytes + EXCLUDED.total_bytes;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+INSERT INTO user_storage_usage (user_addr, entry_count, total_bytes)
+SELECT user_addr, count(*), sum(user_storage_entry_size(user_storage))
+FROM user_storage
+GROUP BY user_addr
+ON CONFLICT (user_addr) DO NOTHING;
+
+DROP TRIGGER IF EXISTS user_storage_usage_trigger ON user_storage;
+CREATE TRIGGER user_storage_usage_trigger
+AFTER INSERT OR UPDATE OR DELETE ON user_storage
+FOR EACH ROW EXECUTE FUNCTION user_storage_track_usage();
@@ -0,0 +1 @@
+ALTER TABLE user_storage_usage DROP COLUMN version_floor;
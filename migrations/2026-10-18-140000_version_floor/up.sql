@@ -0,0 +1,2 @@
+-- the highest version the user reached before an erasure, which new entries start above
+ALTER TABLE user_storage_usage ADD COLUMN version_floor BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE user_storage ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
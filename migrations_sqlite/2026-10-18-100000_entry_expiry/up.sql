@@ -0,0 +1,4 @@
+-- milliseconds since the epoch
+ALTER TABLE user_storage ADD COLUMN expires_at BIGINT;
+
+CREATE INDEX IF NOT EXISTS user_storage_expires_at_idx ON user_storage (expires_at) WHERE expires_at IS NOT NULL;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS user_storage_changes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_addr TEXT NOT NULL,
+    key TEXT NOT NULL,
+    entry TEXT,
+    version BIGINT NOT NULL,
+    -- milliseconds since the epoch
+    created_at BIGINT NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+);
+
+CREATE INDEX IF NOT EXISTS user_storage_changes_user_addr_id_idx ON user_storage_changes (user_addr, id);
+CREATE INDEX IF NOT EXISTS user_storage_changes_created_at_idx ON user_storage_changes (created_at);
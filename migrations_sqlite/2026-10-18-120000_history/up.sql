@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS user_storage_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_addr TEXT NOT NULL,
+    key TEXT NOT NULL,
+    -- NULL for a deletion
+    entry_type TEXT,
+    entry_value_binary TEXT,
+    entry_value_boolean BOOLEAN,
+    entry_value_integer BIGINT,
+    entry_value_json TEXT,
+    entry_value_string TEXT,
+    version BIGINT NOT NULL,
+    expires_at BIGINT,
+    created_at BIGINT NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+);
+
+CREATE INDEX IF NOT EXISTS user_storage_history_user_addr_key_id_idx ON user_storage_history (user_addr, key, id);
+
+-- the history starts with the entries as they are at the moment of migration
+INSERT INTO user_storage_history (
+    user_addr, key, entry_type, entry_value_binary, entry_value_boolean, entry_value_integer,
+    entry_value_json, entry_value_string, version, expires_at
+)
+SELECT
+    user_addr, key, entry_type, entry_value_binary, entry_value_boolean, entry_value_integer,
+    entry_value_json, entry_value_string, version, expires_at
+FROM user_storage;
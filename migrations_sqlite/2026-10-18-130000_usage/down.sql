@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS user_storage_usage_delete_trigger;
+DROP TRIGGER IF EXISTS user_storage_usage_update_trigger;
+DROP TRIGGER IF EXISTS user_storage_usage_insert_trigger;
+DROP TABLE IF EXISTS user_storage_usage;
@@ -0,0 +1,79 @@
+CREATE TABLE IF NOT EXISTS user_storage_usage (
+    user_addr TEXT PRIMARY KEY,
+    entry_count BIGINT NOT NULL DEFAULT 0,
+    total_bytes BIGINT NOT NULL DEFAULT 0
+);
+
+-- there are no SQL functions in SQLite, so the entry size is spelled out in every trigger:
+-- the key plus the value in its textual form, a fixed size for integers and booleans
+
+INSERT INTO user_storage_usage (user_addr, entry_count, total_bytes)
+SELECT user_addr, count(*), sum(length(CAST(key AS BLOB)) + COALESCE(
+    length(CAST(entry_value_binary AS BLOB)),
+    length(CAST(entry_value_string AS BLOB)),
+    length(CAST(entry_value_json AS BLOB)),
+    CASE WHEN entry_value_integer IS NOT NULL THEN 8 END,
+    CASE WHEN entry_value_boolean IS NOT NULL THEN 1 END,
+    0
+))
+FROM user_storage
+GROUP BY user_addr
+ON CONFLICT (user_addr) DO NOTHING;
+
+DROP TRIGGER IF EXISTS user_storage_usage_insert_trigger;
+CREATE TRIGGER user_storage_usage_insert_trigger
+AFTER INSERT ON user_storage
+BEGIN
+    INSERT INTO user_storage_usage (user_addr, entry_count, total_bytes)
+    VALUES (NEW.user_addr, 1, length(CAST(NEW.key AS BLOB)) + COALESCE(
+        length(CAST(NEW.entry_value_binary AS BLOB)),
+        length(CAST(NEW.entry_value_string AS BLOB)),
+        length(CAST(NEW.entry_value_json AS BLOB)),
+        CASE WHEN NEW.entry_value_integer IS NOT NULL THEN 8 END,
+        CASE WHEN NEW.entry_value_boolean IS NOT NULL THEN 1 END,
+        0
+    ))
+    ON CONFLICT (user_addr) DO UPDATE
+    SET entry_count = entry_count + 1,
+        total_bytes = total_bytes + excluded.total_bytes;
+END;
+
+DROP TRIGGER IF EXISTS user_storage_usage_update_trigger;
+CREATE TRIGGER user_storage_usage_update_trigger
+AFTER UPDATE ON user_storage
+BEGIN
+    UPDATE user_storage_usage
+    SET total_bytes = total_bytes - (length(CAST(OLD.key AS BLOB)) + COALESCE(
+        length(CAST(OLD.entry_value_binary AS BLOB)),
+        length(CAST(OLD.entry_value_string AS BLOB)),
+        length(CAST(OLD.entry_value_json AS BLOB)),
+        CASE WHEN OLD.entry_value_integer IS NOT NULL THEN 8 END,
+        CASE WHEN OLD.entry_value_boolean IS NOT NULL THEN 1 END,
+        0
+    )) + (length(CAST(NEW.key AS BLOB)) + COALESCE(
+        length(CAST(NEW.entry_value_binary AS BLOB)),
+        length(CAST(NEW.entry_value_string AS BLOB)),
+        length(CAST(NEW.entry_value_json AS BLOB)),
+        CASE WHEN NEW.entry_value_integer IS NOT NULL THEN 8 END,
+        CASE WHEN NEW.entry_value_boolean IS NOT NULL THEN 1 END,
+        0
+    ))
+    WHERE user_addr = NEW.user_addr;
+END;
+
+DROP TRIGGER IF EXISTS user_storage_usage_delete_trigger;
+CREATE TRIGGER user_storage_usage_delete_trigger
+AFTER DELETE ON user_storage
+BEGIN
+    UPDATE user_storage_usage
+    SET entry_count = entry_count - 1,
+        total_bytes = total_bytes - (length(CAST(OLD.key AS BLOB)) + COALESCE(
+            length(CAST(OLD.entry_value_binary AS BLOB)),
+            length(CAST(OLD.entry_value_string AS BLOB)),
+            length(CAST(OLD.entry_value_json AS BLOB)),
+            CASE WHEN OLD.entry_value_integer IS NOT NULL THEN 8 END,
+            CASE WHEN OLD.entry_value_boolean IS NOT NULL THEN 1 END,
+            0
+        ))
+    WHERE user_addr = OLD.user_addr;
+END;
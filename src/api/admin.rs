@@ -0,0 +1,213 @@
+//! Admin API for the support engineers, acting on the storage of an arbitrary user.
+//! Writes made through it are not limited by the quotas.
+
+use super::{
+    controllers, delete_keys_query, encode_cursor, erase_all_query, marked_stale, set_entry_result,
+    to_json, validate_page, versioned_entry, Precondition,
+};
+use crate::config::api::{Admin, Quota};
+use crate::error::Error;
+use crate::models::dto::{
+    Entry, EntryQuery, ExpiryQuery, KeyEntryList, KeyList, KeyPathList, KeyPrefixQuery, UserList,
+    UserUsage, UsersQuery,
+};
+use crate::models::UserAddress;
+use crate::repo::{Repo, RepoOperations};
+use crate::waves;
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, reject, Filter, Rejection, Reply};
+
+pub(super) fn routes<R: Repo>(
+    admin: &Admin,
+    chain_id: u8,
+    repo: Arc<R>,
+) -> BoxedFilter<(impl Reply,)> {
+    let qs_config = || serde_qs::Config::new(5, false);
+    let with_repo = warp::any().map(move || repo.clone());
+    let unlimited = warp::any().map(Quota::default);
+
+    let user = warp::path("users")
+        .and(warp::path::param::<String>())
+        .and_then(move |user_addr: String| async move {
+            if waves::is_valid_address(&user_addr, chain_id) {
+                Ok(user_addr)
+            } else {
+                Err(reject::custom(Error::ValidationError(
+                    "user_addr".to_string(),
+                    Some(HashMap::from([(
+                        "reason".to_string(),
+                        "not a valid address".to_string(),
+                    )])),
+                )))
+            }
+        });
+    let single_entry = user
+        .and(warp::path("storage"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end());
+    let entries = user.and(warp::path("storage")).and(warp::path::end());
+
+    let list_users = warp::path("users")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<UsersQuery>(qs_config()))
+        .and(with_repo.clone())
+        .and_then(list_users)
+        .map(to_json);
+
+    let get_usage = user
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_repo.clone())
+        .and_then(get_usage)
+        .map(to_json);
+
+    let list_keys = user
+        .and(warp::path("keys"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<KeyPrefixQuery>(qs_config()))
+        .and(with_repo.clone())
+        .and_then(|user_addr, query, repo| controllers::list_keys(query, user_addr, repo))
+        .map(to_json);
+
+    let get_entries = entries
+        .and(warp::get())
+        .and(serde_qs::warp::query::<KeyPathList>(qs_config()))
+        .and(with_repo.clone())
+        .and_then(|user_addr, query, repo| {
+            controllers::get_entries_with_paths(query, user_addr, repo)
+        })
+        .map(|(entries, is_stale)| marked_stale(to_json(entries), is_stale));
+
+    let set_entries = entries
+        .and(warp::put())
+        .and(warp::body::json::<KeyEntryList>())
+        .and(with_repo.clone())
+        .and(unlimited)
+        .and_then(|user_addr, entries, repo, quota| {
+            controllers::set_entries(entries, user_addr, repo, quota)
+        })
+        .map(to_json);
+
+    let delete_all = entries
+        .and(warp::delete())
+        .and(erase_all_query())
+        .and(with_repo.clone())
+        .and_then(|user_addr, query, repo| controllers::delete_all(query, user_addr, repo))
+        .map(to_json);
+
+    let delete_entries = entries
+        .and(warp::delete())
+        .and(delete_keys_query())
+        .and(warp::body::json::<KeyList>())
+        .and(with_repo.clone())
+        .and_then(|user_addr, keys, repo| controllers::delete_entries(keys, user_addr, repo))
+        .map(to_json);
+
+    let get_single_entry = single_entry
+        .and(warp::get())
+        .and(serde_qs::warp::query::<EntryQuery>(qs_config()))
+        .and(with_repo.clone())
+        .and_then(|user_addr, key, query, repo| {
+            controllers::get_single_entry(key, query, user_addr, repo)
+        })
+        .map(|(entry, is_stale)| marked_stale(versioned_entry(entry), is_stale));
+
+    let set_single_entry = single_entry
+        .and(warp::put())
+        .and(serde_qs::warp::query::<ExpiryQuery>(qs_config()))
+        .and(warp::body::json::<Entry>())
+        .and(with_repo.clone())
+        .and(unlimited)
+        .and_then(|user_addr, key, expiry_query, entry, repo, quota| {
+            controllers::set_single_entry(
+                key,
+                Precondition::default(),
+                expiry_query,
+                entry,
+                user_addr,
+                repo,
+                quota,
+            )
+        })
+        .map(set_entry_result);
+
+    let delete_single_entry = single_entry
+        .and(warp::delete())
+        .and(with_repo)
+        .and_then(|user_addr, key, repo| controllers::delete_single_entry(key, user_addr, repo))
+        .map(to_json);
+
+    authorized(admin.token.clone())
+        .and(
+            list_users
+                .or(get_usage)
+                .or(list_keys)
+                .or(get_entries)
+                .or(set_entries)
+                .or(delete_all)
+                .or(delete_entries)
+                .or(get_single_entry)
+                .or(set_single_entry)
+                .or(delete_single_entry),
+        )
+        .boxed()
+}
+
+async fn list_users<R: Repo>(query: UsersQuery, repo: Arc<R>) -> Result<UserList, Rejection> {
+    let (after, limit) = validate_page(query.after.as_deref(), query.limit)?;
+    let mut users = repo
+        .interact(move |ops| ops.list_users(after.as_deref(), limit + 1))
+        .await?;
+
+    let next_cursor = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|(user_addr, _)| encode_cursor(user_addr))
+    } else {
+        None
+    };
+
+    Ok(UserList {
+        users: users.into_iter().map(UserUsage::from).collect(),
+        next_cursor,
+    })
+}
+
+async fn get_usage<R: Repo>(user_addr: UserAddress, repo: Arc<R>) -> Result<UserUsage, Rejection> {
+    let usage = {
+        let user_addr = user_addr.clone();
+        repo.interact(move |ops| ops.usage(&user_addr)).await?
+    };
+    Ok(UserUsage::from((user_addr, usage)))
+}
+
+fn authorized(token: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("Authorization")
+        .and_then(move |authorization: Option<String>| {
+            let presented = authorization
+                .as_deref()
+                .and_then(|authorization| authorization.strip_prefix("Bearer "));
+            let is_authorized = matches!(
+                presented,
+                Some(presented) if constant_time_eq(presented.trim().as_bytes(), token.as_bytes())
+            );
+            async move {
+                if is_authorized {
+                    Ok(())
+                } else {
+                    Err(reject::custom(Error::Unauthorized(
+                        "invalid admin token".to_string(),
+                    )))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Compares without bailing out on the first mismatch, not to reveal the token by timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,349 @@
+//! Filters resolving the user a request is made on behalf of.
+//!
+//! In the JWT mode a request carries an `Authorization: Bearer` token signed with one of
+//! the configured keys, and the user address is taken from the configured claim.
+//!
+//! In the signature mode a request carries the signer's public key (`X-Public-Key`),
+//! a timestamp in milliseconds (`X-Timestamp`) and a signature (`X-Signature`), both keys
+//! base58 encoded. The signed message is
+//! `<timestamp>\n<METHOD>\n<path>[?<query>]\n<If-Match>\n<If-None-Match>\n<Content-Type>\n<body>`,
+//! an absent header signed as an empty line. A signature is accepted once, so a request
+//! failed for whatever reason has to be signed anew to be retried.
+//!
+//! The accepted signatures are kept in memory, so a request may be replayed against another
+//! replica within the short timestamp window: the signature mode supports a single replica.
+
+use crate::config::api::{Auth, Config, JwtConfig};
+use crate::error::Error;
+use crate::models::UserAddress;
+use crate::waves::{self, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use jsonwebtoken::{Algorithm, Validation};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::{
+    filters::BoxedFilter,
+    http::{HeaderMap, Method},
+    hyper::body::{Buf, Bytes},
+    path::FullPath,
+    reject, Filter, Rejection,
+};
+
+const USER_ADDRESS_HEADER: &str = "X-User-Address";
+const JWT_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::RS256, Algorithm::EdDSA];
+/// Headers signed along with the request line, as they change the meaning of the body
+const SIGNED_HEADERS: [&str; 3] = ["If-Match", "If-None-Match", "Content-Type"];
+
+lazy_static! {
+    /// Signatures accepted by this replica lately, shared by all the signed routes.
+    static ref SEEN_SIGNATURES: SeenSignatures = SeenSignatures::default();
+}
+
+/// Signatures accepted within the current and the previous generation, a generation lasting
+/// twice the maximum request age, so that a signature is remembered at least for as long
+/// as its timestamp is acceptable.
+#[derive(Default)]
+struct SeenSignatures {
+    generations: Mutex<Generations>,
+}
+
+#[derive(Default)]
+struct Generations {
+    current: HashSet<[u8; SIGNATURE_LENGTH]>,
+    previous: HashSet<[u8; SIGNATURE_LENGTH]>,
+    started_at: Option<Instant>,
+}
+
+impl SeenSignatures {
+    /// Remembers the signature, returning `false` if it was seen already.
+    fn insert(&self, signature: [u8; SIGNATURE_LENGTH], max_age: Duration) -> bool {
+        let now = Instant::now();
+        let mut generations = self.generations.lock().unwrap();
+        match generations.started_at {
+            Some(started_at) if now.duration_since(started_at) < max_age * 2 => {}
+            _ => {
+                generations.previous = mem::take(&mut generations.current);
+                generations.started_at = Some(now);
+            }
+        }
+
+        !generations.previous.contains(&signature) && generations.current.insert(signature)
+    }
+}
+
+/// The user of a request without a body.
+pub(super) fn user_addr(config: &Config) -> BoxedFilter<(UserAddress,)> {
+    let chain_id = config.chain_id;
+    match &config.auth {
+        Auth::Header => warp::header::<String>(USER_ADDRESS_HEADER)
+            .and_then(move |user_addr: String| async move {
+                validate_address(&user_addr, chain_id)
+                    .map(|_| user_addr)
+                    .map_err(reject::custom)
+            })
+            .boxed(),
+        Auth::Signature { max_age } => {
+            signed(chain_id, *max_age, warp::any().map(Bytes::new).boxed())
+                .map(|_, user_addr| user_addr)
+                .boxed()
+        }
+        Auth::Jwt(jwt) => bearer(Arc::new(jwt.clone()), chain_id).boxed(),
+    }
+}
+
+/// The JSON body and the user of a request.
+pub(super) fn json_body<T>(config: &Config) -> BoxedFilter<(T, UserAddress)>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    match &config.auth {
+        Auth::Header | Auth::Jwt(_) => warp::body::json::<T>().and(user_addr(config)).boxed(),
+        Auth::Signature { max_age } => {
+            signed(config.chain_id, *max_age, warp::body::bytes().boxed())
+                .and_then(|body: Bytes, user_addr| async move {
+                    serde_json::from_slice(&body)
+                        .map(|body| (body, user_addr))
+                        .map_err(|e| {
+                            reject::custom(Error::ValidationError(
+                                "body".to_string(),
+                                Some(HashMap::from([("reason".to_string(), e.to_string())])),
+                            ))
+                        })
+                })
+                .untuple_one()
+                .boxed()
+        }
+    }
+}
+
+/// The raw body, up to the configured size, and the user of a request.
+pub(super) fn bytes_body(config: &Config) -> BoxedFilter<(Bytes, UserAddress)> {
+    let body = limited_bytes(config.max_raw_body_size);
+    match &config.auth {
+        Auth::Header | Auth::Jwt(_) => body.and(user_addr(config)).boxed(),
+        Auth::Signature { max_age } => signed(config.chain_id, *max_age, body).boxed(),
+    }
+}
+
+/// The body, rejected as soon as it turns out to be larger than `limit` bytes
+/// rather than buffered whole first.
+fn limited_bytes(limit: u64) -> BoxedFilter<(Bytes,)> {
+    warp::header::optional::<u64>("Content-Length")
+        .and(warp::body::stream())
+        .and_then(move |length: Option<u64>, chunks| async move {
+            if matches!(length, Some(length) if length > limit) {
+                return Err(reject::custom(Error::PayloadTooLarge(limit)));
+            }
+            read_body(chunks, limit).await.map_err(reject::custom)
+        })
+        .boxed()
+}
+
+async fn read_body<S, B>(chunks: S, limit: u64) -> Result<Bytes, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures::pin_mut!(chunks);
+    let mut body = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let mut chunk = chunk.map_err(|e| {
+            Error::ValidationError(
+                "body".to_string(),
+                Some(HashMap::from([("reason".to_string(), e.to_string())])),
+            )
+        })?;
+        if (body.len() + chunk.remaining()) as u64 > limit {
+            return Err(Error::PayloadTooLarge(limit));
+        }
+        while chunk.has_remaining() {
+            let read = chunk.chunk().len();
+            body.extend_from_slice(chunk.chunk());
+            chunk.advance(read);
+        }
+    }
+    Ok(Bytes::from(body))
+}
+
+fn validate_address(user_addr: &str, chain_id: u8) -> Result<(), Error> {
+    if waves::is_valid_address(user_addr, chain_id) {
+        return Ok(());
+    }
+    Err(Error::ValidationError(
+        USER_ADDRESS_HEADER.to_string(),
+        Some(HashMap::from([(
+            "reason".to_string(),
+            "not a valid address".to_string(),
+        )])),
+    ))
+}
+
+fn signed(
+    chain_id: u8,
+    max_age: Duration,
+    body: BoxedFilter<(Bytes,)>,
+) -> impl Filter<Extract = (Bytes, UserAddress), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(body)
+        .and_then(
+            move |method: Method,
+                  path: FullPath,
+                  query: String,
+                  headers: HeaderMap,
+                  body: Bytes| {
+                async move {
+                    let mut target = path.as_str().to_string();
+                    if !query.is_empty() {
+                        target.push('?');
+                        target.push_str(&query);
+                    }
+
+                    verify(&method, &target, &headers, &body, chain_id, max_age)
+                        .map(|user_addr| (body, user_addr))
+                        .map_err(reject::custom)
+                }
+            },
+        )
+        .untuple_one()
+}
+
+fn verify(
+    method: &Method,
+    target: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    chain_id: u8,
+    max_age: Duration,
+) -> Result<UserAddress, Error> {
+    let unauthorized = |reason: &str| Error::Unauthorized(reason.to_string());
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| unauthorized(&format!("missing {name} header")))
+    };
+
+    let public_key: [u8; PUBLIC_KEY_LENGTH] = decode_base58(header("X-Public-Key")?)
+        .ok_or_else(|| unauthorized("malformed public key"))?;
+    let signature: [u8; SIGNATURE_LENGTH] =
+        decode_base58(header("X-Signature")?).ok_or_else(|| unauthorized("malformed signature"))?;
+    let timestamp = header("X-Timestamp")?;
+
+    let age = timestamp
+        .parse::<i64>()
+        .map_err(|_| unauthorized("malformed timestamp"))?
+        .abs_diff(Utc::now().timestamp_millis());
+    if age > max_age.as_millis() as u64 {
+        return Err(unauthorized("timestamp is too far from the current time"));
+    }
+
+    let mut message = format!("{timestamp}\n{method}\n{target}\n");
+    for name in SIGNED_HEADERS {
+        let value = headers.get(name).and_then(|value| value.to_str().ok());
+        message.push_str(value.unwrap_or_default());
+        message.push('\n');
+    }
+    let mut message = message.into_bytes();
+    message.extend_from_slice(body);
+    if !waves::verify_signature(&public_key, &message, &signature) {
+        return Err(unauthorized("invalid signature"));
+    }
+    // a request rejected by a route after its signature was accepted is verified again
+    // by the next matching routes, but it's the first rejection that is reported
+    if !SEEN_SIGNATURES.insert(signature, max_age) {
+        return Err(unauthorized("signature has been used already"));
+    }
+
+    let user_addr = waves::address_from_public_key(&public_key, chain_id);
+    match headers.get(USER_ADDRESS_HEADER) {
+        Some(claimed) if claimed.as_bytes() != user_addr.as_bytes() => {
+            Err(unauthorized("X-User-Address does not match the public key"))
+        }
+        _ => Ok(user_addr),
+    }
+}
+
+fn bearer(
+    config: Arc<JwtConfig>,
+    chain_id: u8,
+) -> impl Filter<Extract = (UserAddress,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("Authorization")
+        .and(warp::header::optional::<String>(USER_ADDRESS_HEADER))
+        .and_then(
+            move |authorization: Option<String>, claimed: Option<String>| {
+                let config = config.clone();
+                async move {
+                    verify_token(
+                        &config,
+                        chain_id,
+                        authorization.as_deref(),
+                        claimed.as_deref(),
+                    )
+                    .map_err(reject::custom)
+                }
+            },
+        )
+}
+
+fn verify_token(
+    config: &JwtConfig,
+    chain_id: u8,
+    authorization: Option<&str>,
+    claimed: Option<&str>,
+) -> Result<UserAddress, Error> {
+    let unauthorized = |reason: &str| Error::Unauthorized(reason.to_string());
+
+    let token = authorization
+        .ok_or_else(|| unauthorized("missing Authorization header"))?
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| unauthorized("not a bearer token"))?
+        .trim();
+
+    let header = jsonwebtoken::decode_header(token).map_err(|_| unauthorized("malformed token"))?;
+    if !JWT_ALGORITHMS.contains(&header.alg) {
+        return Err(unauthorized("unsupported token algorithm"));
+    }
+    // a token without a key id is verified with the only key there is
+    let key = match (&header.kid, config.keys.as_slice()) {
+        (Some(kid), keys) => keys.iter().find(|(id, _)| id.as_ref() == Some(kid)),
+        (None, [key]) => Some(key),
+        (None, _) => None,
+    };
+    let (_, key) = key.ok_or_else(|| unauthorized("unknown token key"))?;
+
+    let claims =
+        jsonwebtoken::decode::<HashMap<String, Value>>(token, key, &Validation::new(header.alg))
+            .map_err(|e| unauthorized(&e.to_string()))?
+            .claims;
+
+    let user_addr = match claims.get(&config.address_claim) {
+        Some(Value::String(user_addr)) if waves::is_valid_address(user_addr, chain_id) => {
+            user_addr.clone()
+        }
+        _ => {
+            return Err(Error::Forbidden(format!(
+                "token has no valid {} claim",
+                config.address_claim
+            )))
+        }
+    };
+    match claimed {
+        Some(claimed) if claimed != user_addr => Err(Error::Forbidden(
+            "X-User-Address does not match the token".to_string(),
+        )),
+        _ => Ok(user_addr),
+    }
+}
+
+fn decode_base58<const N: usize>(value: &str) -> Option<[u8; N]> {
+    bs58::decode(value).into_vec().ok()?.try_into().ok()
+}
@@ -0,0 +1,1672 @@
+mod admin;
+mod auth;
+pub mod rate_limit;
+mod ws;
+
+use crate::api::rate_limit::RateLimitBackend;
+use crate::changes::ChangeFeed;
+use crate::config::api::{Admin, Config, Quota};
+use crate::error::Error;
+use crate::models::dto::{
+    Change, ChangesQuery, Entry, EntryQuery, EraseQuery, ErasureResult, ExpiryQuery, ExportFormat,
+    ExportQuery, HistoryQuery, ImportQuery, ImportStrategy, ImportSummary, IncrRequest,
+    KeyEntryList, KeyEntryPair, KeyList, KeyListing, KeyPathList, KeyPrefixQuery,
+    NullableEntryList, PageQuery, RestoreRequest, Revision, RevisionList,
+};
+use crate::models::{UserStorageEntry, UserStorageUsage};
+use crate::repo::{
+    cache::{CACHE_HITS, CACHE_MISSES},
+    Repo,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{CONTENT_TYPE, ETAG, RETRY_AFTER, WARNING},
+        StatusCode,
+    },
+    hyper::{body::Bytes, Body},
+    reject,
+    reply::{json, reply, with_header, with_status, Json, Reply, Response},
+    sse,
+    ws::Ws,
+    Filter, Rejection,
+};
+use wavesexchange_log::{error, info};
+use wavesexchange_warp::error::{
+    error_handler_with_serde_qs, handler, internal, not_found, validation, ErrorListResponse,
+    ErrorResponse,
+};
+use wavesexchange_warp::log::access;
+use wavesexchange_warp::MetricsWarpBuilder;
+
+const ERROR_CODES_PREFIX: u16 = 95;
+const MAX_JSON_PAYLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+const MAX_PAGE_LIMIT: u32 = 1000;
+const CHANGES_BUFFER_SIZE: usize = 100;
+const EXPORT_BATCH_SIZE: i64 = 1000;
+/// Rows per statement when importing, well below the Postgres limit of bind parameters.
+const IMPORT_BATCH_SIZE: usize = 1000;
+const NDJSON_MEDIA_TYPE: &str = "application/x-ndjson";
+
+pub async fn start(
+    config: Config,
+    user_storage: Arc<impl Repo>,
+    changes: ChangeFeed,
+    rate_limiter: Arc<dyn RateLimitBackend>,
+) {
+    if let Some(admin) = &config.admin {
+        let admin_routes = admin_routes(admin, config.chain_id, user_storage.clone())
+            .with(warp::log::custom(access));
+
+        info!("Starting admin API server at 0.0.0.0:{}", admin.port);
+        tokio::spawn(warp::serve(admin_routes).run(([0, 0, 0, 0], admin.port)));
+    }
+
+    let routes =
+        routes(&config, user_storage, changes, rate_limiter).with(warp::log::custom(access));
+
+    info!("Starting API server at 0.0.0.0:{}", config.port);
+
+    MetricsWarpBuilder::new()
+        .with_main_routes(routes)
+        .with_main_routes_port(config.port)
+        .with_metrics_port(config.metrics_port)
+        .with_metric(&*CACHE_HITS)
+        .with_metric(&*CACHE_MISSES)
+        .run_async()
+        .await;
+}
+
+/// The storage API filter, built apart from the server so that it can be driven
+/// by `warp::test` as well.
+pub fn routes<R: Repo>(
+    config: &Config,
+    user_storage: Arc<R>,
+    changes: ChangeFeed,
+    rate_limiter: Arc<dyn RateLimitBackend>,
+) -> BoxedFilter<(impl Reply,)> {
+    let error_handler = handler(ERROR_CODES_PREFIX, handle_error);
+
+    let qs_config = || serde_qs::Config::new(5, false);
+    let path_prefix = warp::path!("storage" / ..);
+    let limiter = rate_limit::Limiter::new(config.rate_limit, rate_limiter);
+    let user_addr = limiter.user(auth::user_addr(config));
+    let precondition = warp::header::optional::<String>("If-Match")
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .map(Precondition::from_headers);
+
+    let with_user_storage = warp::any().map(move || user_storage.clone());
+    let with_changes = warp::any().map(move || changes.clone());
+    let quota = config.quota;
+    let with_quota = warp::any().map(move || quota);
+
+    // the paged and the whole reads share the routes, so that a rejected page
+    // is not served by the other one
+    let get_entries = warp::path::end()
+        .and(warp::get())
+        .and(serde_qs::warp::query::<PageQuery>(qs_config()))
+        .and(serde_qs::warp::query::<KeyPathList>(qs_config()))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::get_entries_or_page);
+
+    let get_entries_post = warp::path::end()
+        .and(warp::post())
+        .and(serde_qs::warp::query::<PageQuery>(qs_config()))
+        .and(limiter.body(auth::json_body::<KeyPathList>(config)))
+        .and(with_user_storage.clone())
+        .and_then(controllers::get_entries_or_page);
+
+    let set_entries = warp::path::end()
+        .and(warp::put())
+        .and(limiter.body(auth::json_body::<KeyEntryList>(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::set_entries)
+        .map(to_json);
+
+    let delete_all = warp::path::end()
+        .and(warp::delete())
+        .and(erase_all_query())
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::delete_all)
+        .map(to_json);
+
+    let delete_entries = warp::path::end()
+        .and(warp::delete())
+        .and(delete_keys_query())
+        .and(limiter.body(auth::json_body::<KeyList>(config)))
+        .and(with_user_storage.clone())
+        .and_then(controllers::delete_entries)
+        .map(to_json);
+
+    let list_keys = warp::path("keys")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<KeyPrefixQuery>(qs_config()))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::list_keys)
+        .map(to_json);
+
+    let export_entries = warp::path("export")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<ExportQuery>(qs_config()))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::export_entries);
+
+    let import_entries = warp::path("import")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(serde_qs::warp::query::<ImportQuery>(qs_config()))
+        .and(warp::header::optional::<String>("Content-Type"))
+        .and(limiter.body(auth::bytes_body(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::import_entries)
+        .map(to_json);
+
+    let subscribe_changes = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<ChangesQuery>(qs_config()))
+        .and(warp::header::optional::<String>("Last-Event-ID"))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and(with_changes.clone())
+        .and_then(controllers::subscribe_changes)
+        .map(|changes| sse::reply(sse::keep_alive().stream(changes)));
+
+    let subscribe_ws = warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(user_addr.clone())
+        .and(limiter.client_ip())
+        .and(with_user_storage.clone())
+        .and(with_changes.clone())
+        .and(with_quota)
+        .map({
+            let limiter = limiter.clone();
+            move |upgrade: Ws, user_addr: String, ip, repo, changes: ChangeFeed, quota: Quota| {
+                let client = ws::Client {
+                    user_addr,
+                    ip,
+                    limiter: limiter.clone(),
+                };
+                upgrade.on_upgrade(move |socket| ws::serve(socket, client, repo, changes, quota))
+            }
+        });
+
+    let incr_entry = warp::path::param::<String>()
+        .and(warp::path("incr"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(limiter.body(auth::json_body::<IncrRequest>(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::incr_entry)
+        .map(versioned_entry);
+
+    let restore_entry = warp::path::param::<String>()
+        .and(warp::path("restore"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(limiter.body(auth::json_body::<RestoreRequest>(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::restore_entry)
+        .map(versioned_entry);
+
+    let get_history = warp::path::param::<String>()
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<HistoryQuery>(qs_config()))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::get_history)
+        .map(to_json);
+
+    let get_single_entry = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(serde_qs::warp::query::<EntryQuery>(qs_config()))
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::get_single_entry)
+        .map(|(entry, is_stale)| marked_stale(versioned_entry(entry), is_stale));
+
+    let set_single_entry = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(precondition)
+        .and(serde_qs::warp::query::<ExpiryQuery>(qs_config()))
+        .and(limiter.body(auth::json_body::<Entry>(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::set_single_entry)
+        .map(set_entry_result);
+
+    let patch_single_entry = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(precondition)
+        .and(warp::header::<String>("Content-Type"))
+        .and(limiter.body(auth::bytes_body(config)))
+        .and(with_user_storage.clone())
+        .and(with_quota)
+        .and_then(controllers::patch_single_entry)
+        .map(versioned_entry);
+
+    let delete_single_entry = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(user_addr.clone())
+        .and(with_user_storage.clone())
+        .and_then(controllers::delete_single_entry)
+        .map(to_json);
+
+    path_prefix
+        .and(
+            get_entries
+                .or(get_entries_post)
+                .or(set_entries)
+                .or(delete_all)
+                .or(delete_entries)
+                .or(list_keys)
+                .or(export_entries)
+                .or(import_entries)
+                .or(subscribe_changes)
+                .or(subscribe_ws)
+                .or(incr_entry)
+                .or(get_history)
+                .or(restore_entry)
+                .or(get_single_entry)
+                .or(set_single_entry)
+                .or(patch_single_entry)
+                .or(delete_single_entry),
+        )
+        .recover(recover_rate_limited)
+        .recover(move |rej| {
+            error!("{:?}", rej);
+            error_handler_with_serde_qs(ERROR_CODES_PREFIX, error_handler.clone())(rej)
+        })
+        .boxed()
+}
+
+/// The admin API filter, see `admin`.
+pub fn admin_routes<R: Repo>(
+    admin: &Admin,
+    chain_id: u8,
+    user_storage: Arc<R>,
+) -> BoxedFilter<(impl Reply,)> {
+    let error_handler = handler(ERROR_CODES_PREFIX, handle_error);
+    warp::path("admin")
+        .and(admin::routes(admin, chain_id, user_storage))
+        .recover(move |rej| {
+            error!("{:?}", rej);
+            error_handler_with_serde_qs(ERROR_CODES_PREFIX, error_handler.clone())(rej)
+        })
+        .boxed()
+}
+
+fn handle_error(err: &Error) -> ErrorListResponse {
+    match err {
+        Error::ValidationError(field, error_details) => {
+            let mut error_details = error_details.to_owned();
+            if let Some(details) = error_details.as_mut() {
+                details.insert("parameter".to_owned(), field.to_owned());
+            }
+            validation::invalid_parameter(ERROR_CODES_PREFIX, error_details)
+        }
+        Error::KeyNotFound(_) => not_found(ERROR_CODES_PREFIX),
+        Error::PayloadTooLarge(max_size) => error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Payload Too Large",
+            Some(HashMap::from([(
+                "max_size".to_owned(),
+                max_size.to_string(),
+            )])),
+        ),
+        Error::UnsupportedMediaType(media_type) => error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported Media Type",
+            Some(HashMap::from([(
+                "content_type".to_owned(),
+                media_type.to_owned(),
+            )])),
+        ),
+        Error::Unauthorized(reason) => error_response(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+            Some(HashMap::from([("reason".to_owned(), reason.to_owned())])),
+        ),
+        Error::Forbidden(reason) => error_response(
+            StatusCode::FORBIDDEN,
+            "Forbidden",
+            Some(HashMap::from([("reason".to_owned(), reason.to_owned())])),
+        ),
+        Error::PreconditionFailed(key) => error_response(
+            StatusCode::PRECONDITION_FAILED,
+            "Precondition Failed",
+            Some(HashMap::from([("key".to_owned(), key.to_owned())])),
+        ),
+        err if err.is_unavailable() => error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Service Unavailable",
+            Some(HashMap::from([(
+                "reason".to_owned(),
+                "storage is unavailable".to_owned(),
+            )])),
+        ),
+
+        _ => internal(ERROR_CODES_PREFIX),
+    }
+}
+
+mod controllers {
+    use crate::changes;
+    use crate::repo::RepoOperations;
+
+    use super::*;
+    use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    pub(super) async fn get_entries<R: Repo>(
+        keys: KeyList,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<(NullableEntryList, bool), Rejection> {
+        let keys = keys.keys;
+        let search_keys = keys.clone();
+        let (raw_entries, is_stale) = repo
+            .interact_tracking_stale(move |ops| ops.mget(&user_addr, &search_keys))
+            .await?;
+        let mut entries: HashMap<String, Entry> = HashMap::from_iter(
+            raw_entries
+                .into_iter()
+                .map(|e| (e.key.clone(), Entry::from(e))),
+        );
+
+        let entries = NullableEntryList {
+            entries: keys.into_iter().map(|key| entries.remove(&key)).collect(),
+        };
+        Ok((entries, is_stale))
+    }
+
+    /// Reads the entries of the given keys, or a page of the existing ones
+    /// in the order of the keys if the page is asked for.
+    pub(super) async fn get_entries_or_page<R: Repo>(
+        page: PageQuery,
+        query: KeyPathList,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<Response, Rejection> {
+        if page.limit.is_none() && page.after.is_none() {
+            let (entries, is_stale) = get_entries_with_paths(query, user_addr, repo).await?;
+            return Ok(marked_stale(to_json(entries), is_stale));
+        }
+
+        if !query.paths.is_empty() {
+            return Err(reject::custom(Error::ValidationError(
+                "paths".to_string(),
+                Some(HashMap::from([(
+                    "reason".to_string(),
+                    "paths are not supported in pages".to_string(),
+                )])),
+            )));
+        }
+        let (after, limit) = validate_page(page.after.as_deref(), page.limit)?;
+        let keys = query.keys;
+        let raw_entries = repo
+            .interact(move |ops| ops.mget_page(&user_addr, &keys, after.as_deref(), limit + 1))
+            .await?;
+
+        Ok(to_json(into_listing(raw_entries, limit, true)).into_response())
+    }
+
+    pub(super) async fn list_keys<R: Repo>(
+        query: KeyPrefixQuery,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<KeyListing, Rejection> {
+        let (after, limit) = validate_page(query.after.as_deref(), query.limit)?;
+        let raw_entries = repo
+            .interact(move |ops| {
+                ops.scan_prefix(&user_addr, &query.prefix, after.as_deref(), limit + 1)
+            })
+            .await?;
+
+        Ok(into_listing(raw_entries, limit, query.with_entries))
+    }
+
+    /// Streams all the entries of the user, reading them page by page,
+    /// so the export is not a snapshot if the entries change meanwhile.
+    pub(super) async fn export_entries<R: Repo>(
+        query: ExportQuery,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<Response, Rejection> {
+        // the state is the key to continue after, `None` once the last page is read
+        let pages = stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+            let (user_addr, repo) = (user_addr.clone(), repo.clone());
+            async move {
+                let after = match after {
+                    Some(after) => after,
+                    None => return Ok(None),
+                };
+                let page = repo
+                    .interact(move |ops| {
+                        ops.scan_prefix(&user_addr, "", after.as_deref(), EXPORT_BATCH_SIZE)
+                    })
+                    .await?;
+                let next = if page.len() as i64 == EXPORT_BATCH_SIZE {
+                    Some(page.last().map(|e| e.key.clone()))
+                } else {
+                    None
+                };
+                Ok::<_, Error>(Some((page, next)))
+            }
+        });
+
+        let format = query.format;
+        let (head, tail, content_type) = match format {
+            ExportFormat::Json => ("{\"entries\":[", "]}", "application/json"),
+            ExportFormat::Ndjson => ("", "", NDJSON_MEDIA_TYPE),
+        };
+        let chunks = pages
+            .enumerate()
+            .map(move |(i, page)| page.and_then(|page| export_chunk(format, i == 0, page)))
+            .inspect_err(|e| error!("Failed to export entries: {}", e));
+        let body = stream::once(future::ok(Bytes::from_static(head.as_bytes())))
+            .chain(chunks)
+            .chain(stream::once(future::ok(Bytes::from_static(
+                tail.as_bytes(),
+            ))));
+
+        Ok(with_header(
+            Response::new(Body::wrap_stream(body)),
+            CONTENT_TYPE,
+            content_type,
+        )
+        .into_response())
+    }
+
+    /// Applies an exported document or NDJSON stream in a single transaction.
+    /// The expected versions of the imported entries are ignored.
+    pub(super) async fn import_entries<R: Repo>(
+        query: ImportQuery,
+        content_type: Option<String>,
+        body: Bytes,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<ImportSummary, Rejection> {
+        // the last of the duplicate keys wins, as if the entries were set one by one
+        let mut entries = BTreeMap::new();
+        for pair in parse_import(content_type.as_deref(), &body)? {
+            let entry = pair.entry.ok_or_else(|| {
+                Error::ValidationError(
+                    pair.key.clone(),
+                    Some(HashMap::from([(
+                        "reason".to_string(),
+                        "entry is required".to_string(),
+                    )])),
+                )
+            })?;
+            validate_entry(&pair.key, &entry)?;
+            let expires_at = expiry(&pair.key, pair.ttl_seconds, pair.expires_at)?;
+            entries.insert(pair.key, (entry, expires_at));
+        }
+
+        let now = Utc::now();
+        let imported_count = entries.len();
+        entries.retain(|_, (_, expires_at)| !matches!(expires_at, Some(at) if *at <= now));
+        let expired_count = imported_count - entries.len();
+
+        let summary = repo
+            .transaction(move |ops| {
+                let usage = usage_before(ops, &quota, &user_addr)?;
+                let mut summary = ImportSummary {
+                    skipped: expired_count,
+                    ..ImportSummary::default()
+                };
+                let mut changes = vec![];
+
+                if let ImportStrategy::ReplaceAll = query.strategy {
+                    let mut stale_keys = vec![];
+                    let mut after = None;
+                    loop {
+                        let page = ops.scan_prefix(
+                            &user_addr,
+                            "",
+                            after.as_deref(),
+                            IMPORT_BATCH_SIZE as i64,
+                        )?;
+                        let is_full_page = page.len() == IMPORT_BATCH_SIZE;
+                        after = page.last().map(|e| e.key.clone());
+                        stale_keys.extend(
+                            page.into_iter()
+                                .map(|e| e.key)
+                                .filter(|key| !entries.contains_key(key)),
+                        );
+                        if !is_full_page {
+                            break;
+                        }
+                    }
+
+                    for batch in stale_keys.chunks(IMPORT_BATCH_SIZE) {
+                        let deleted = ops.mdel(&user_addr, batch)?;
+                        summary.deleted += deleted.len();
+                        changes.extend(
+                            deleted
+                                .into_iter()
+                                .map(|(key, version)| Change::deleted(key, version)),
+                        );
+                    }
+                }
+
+                let keys = entries.keys().cloned().collect::<Vec<_>>();
+                let mut existing_keys = HashSet::new();
+                for batch in keys.chunks(IMPORT_BATCH_SIZE) {
+                    existing_keys.extend(
+                        ops.mget_for_update(&user_addr, batch)?
+                            .into_iter()
+                            .map(|e| e.key),
+                    );
+                }
+                if let ImportStrategy::SkipExisting = query.strategy {
+                    entries.retain(|key, _| !existing_keys.contains(key));
+                    summary.skipped += keys.len() - entries.len();
+                }
+
+                let entries_to_update = entries
+                    .iter()
+                    .map(|(key, (entry, expires_at))| {
+                        if existing_keys.contains(key) {
+                            summary.updated += 1;
+                        } else {
+                            summary.created += 1;
+                        }
+                        UserStorageEntry {
+                            expires_at: *expires_at,
+                            ..UserStorageEntry::from((
+                                user_addr.clone(),
+                                key.clone(),
+                                entry.clone(),
+                            ))
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                for batch in entries_to_update.chunks(IMPORT_BATCH_SIZE) {
+                    let updated = ops.mset(batch)?;
+                    changes.extend(updated.into_iter().filter_map(|(key, version)| {
+                        let (entry, _) = entries.remove(&key)?;
+                        Some(Change::updated(key, entry, version))
+                    }));
+                }
+
+                check_quota(ops, &quota, &user_addr, "entries", usage)?;
+
+                if !changes.is_empty() {
+                    ops.publish(&user_addr, &changes)?;
+                }
+                Ok(summary)
+            })
+            .await?;
+
+        Ok(summary)
+    }
+
+    pub(super) async fn set_entries<R: Repo>(
+        entries: KeyEntryList,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<NullableEntryList, Rejection> {
+        let key_entry_pairs = entries.entries.iter().map(|pair| (&pair.key, &pair.entry));
+
+        // clone an iterator, not a vector
+        for (key, entry) in key_entry_pairs.clone() {
+            if let Some(e) = entry {
+                validate_entry(&key, e)?;
+            }
+        }
+
+        let entries_to_update = entries
+            .entries
+            .iter()
+            .filter_map(|pair| {
+                pair.entry.as_ref().map(|entry| {
+                    Ok(UserStorageEntry {
+                        expires_at: expiry(&pair.key, pair.ttl_seconds, pair.expires_at)?,
+                        ..UserStorageEntry::from((
+                            user_addr.clone(),
+                            pair.key.clone(),
+                            entry.clone(),
+                        ))
+                    })
+                })
+            })
+            .collect::<Result<Vec<_>, Rejection>>()?;
+
+        let mut new_entries = entries
+            .entries
+            .iter()
+            .filter_map(|pair| pair.entry.clone().map(|entry| (pair.key.clone(), entry)))
+            .collect::<HashMap<_, _>>();
+
+        let keys = key_entry_pairs
+            .clone()
+            .map(|pair| pair.0.clone())
+            .collect::<Vec<_>>();
+
+        let (old_entries, _) =
+            get_entries(KeyList { keys }, user_addr.clone(), repo.clone()).await?;
+
+        let keys_to_delete = key_entry_pairs
+            .filter_map(|pair| match pair.1 {
+                Some(_) => None,
+                None => Some(pair.0.clone()),
+            })
+            .collect::<Vec<_>>();
+
+        let expected_versions = entries
+            .entries
+            .iter()
+            .filter_map(|pair| pair.version.map(|version| (pair.key.clone(), version)))
+            .collect::<HashMap<_, _>>();
+
+        repo.transaction(move |ops| {
+            let usage = usage_before(ops, &quota, &user_addr)?;
+
+            if !expected_versions.is_empty() {
+                let keys = expected_versions.keys().collect::<Vec<_>>();
+                let current_versions = ops
+                    .mget_for_update(&user_addr, &keys)?
+                    .into_iter()
+                    .map(|e| (e.key, e.version))
+                    .collect::<HashMap<_, _>>();
+
+                for (key, version) in expected_versions.iter() {
+                    if current_versions.get(key) != Some(version) {
+                        return Err(Error::PreconditionFailed(key.clone()));
+                    }
+                }
+            }
+
+            let mut changes = vec![];
+
+            if !keys_to_delete.is_empty() {
+                let deleted = ops.mdel(&user_addr, &keys_to_delete)?;
+                changes.extend(
+                    deleted
+                        .into_iter()
+                        .map(|(key, version)| Change::deleted(key, version)),
+                );
+            }
+
+            if !entries_to_update.is_empty() {
+                let updated = ops.mset(&entries_to_update)?;
+                changes.extend(updated.into_iter().filter_map(|(key, version)| {
+                    let entry = new_entries.remove(&key)?;
+                    Some(Change::updated(key, entry, version))
+                }));
+            }
+
+            check_quota(ops, &quota, &user_addr, "entries", usage)?;
+
+            if !changes.is_empty() {
+                ops.publish(&user_addr, &changes)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        Ok(old_entries)
+    }
+
+    pub(super) async fn delete_entries<R: Repo>(
+        keys: KeyList,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<NullableEntryList, Rejection> {
+        let (old_entries, _) = get_entries(keys.clone(), user_addr.clone(), repo.clone()).await?;
+
+        repo.transaction(move |ops| delete_and_publish(ops, &user_addr, &keys.keys))
+            .await?;
+
+        Ok(old_entries)
+    }
+
+    /// Erases the user, the confirmation is the user address repeated.
+    pub(super) async fn delete_all<R: Repo>(
+        query: EraseQuery,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<ErasureResult, Rejection> {
+        if query.confirm.as_deref() != Some(user_addr.as_str()) {
+            return Err(reject::custom(Error::ValidationError(
+                "confirm".to_string(),
+                Some(HashMap::from([(
+                    "reason".to_string(),
+                    "must be the address of the user".to_string(),
+                )])),
+            )));
+        }
+
+        // the subscribers are notified of the erasure as a whole, recording the deletions
+        // of the keys would keep them after the erasure
+        let deleted = repo
+            .transaction(move |ops| ops.delete_all(&user_addr))
+            .await?;
+        info!("Erased all the data of a user, {} entries", deleted);
+
+        Ok(ErasureResult { deleted })
+    }
+
+    pub(super) async fn get_entries_with_paths<R: Repo>(
+        query: KeyPathList,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<(NullableEntryList, bool), Rejection> {
+        if query.paths.is_empty() {
+            return get_entries(KeyList { keys: query.keys }, user_addr, repo).await;
+        }
+
+        let paths = query
+            .paths
+            .iter()
+            .map(|(key, path)| Ok((key.clone(), parse_json_path(path)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        let keys = query.keys;
+        let search_keys = keys.clone();
+        let (mut entries, is_stale) = repo
+            .interact_tracking_stale(move |ops| {
+                let mut entries = HashMap::new();
+
+                let plain_keys = search_keys
+                    .iter()
+                    .filter(|key| !paths.contains_key(*key))
+                    .collect::<Vec<_>>();
+                for e in ops.mget(&user_addr, &plain_keys)? {
+                    entries.insert(e.key.clone(), Entry::from(e));
+                }
+
+                for key in search_keys.iter().filter(|key| paths.contains_key(*key)) {
+                    if let Some(e) = ops.get_path(&user_addr, key, &paths[key])? {
+                        if let Some(entry) = projected(e)? {
+                            entries.insert(key.clone(), entry);
+                        }
+                    }
+                }
+
+                Ok(entries)
+            })
+            .await?;
+
+        let entries = NullableEntryList {
+            entries: keys.into_iter().map(|key| entries.remove(&key)).collect(),
+        };
+        Ok((entries, is_stale))
+    }
+
+    pub(super) async fn get_single_entry<R: Repo>(
+        key: String,
+        query: EntryQuery,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<((Entry, i64), bool), Rejection> {
+        let path = query.path.as_deref().map(parse_json_path).transpose()?;
+        if path.is_some() && query.at.is_some() {
+            return Err(reject::custom(Error::ValidationError(
+                "at".to_string(),
+                Some(HashMap::from([(
+                    "reason".to_string(),
+                    "at and path are mutually exclusive".to_string(),
+                )])),
+            )));
+        }
+
+        let entry = repo
+            .interact_tracking_stale(move |ops| {
+                let entry = match (path, query.at) {
+                    (_, Some(at)) => ops.get_at(&user_addr, &key, at)?.map(|e| {
+                        let version = e.version;
+                        (Entry::from(e), version)
+                    }),
+                    (Some(path), None) => match ops.get_path(&user_addr, &key, &path)? {
+                        Some(e) => {
+                            let version = e.version;
+                            projected(e)?.map(|entry| (entry, version))
+                        }
+                        None => None,
+                    },
+                    (None, None) => ops.get(&user_addr, &key)?.map(|e| {
+                        let version = e.version;
+                        (Entry::from(e), version)
+                    }),
+                };
+
+                entry.ok_or(Error::KeyNotFound(key))
+            })
+            .await?;
+
+        Ok(entry)
+    }
+
+    pub(super) async fn get_history<R: Repo>(
+        key: String,
+        query: HistoryQuery,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<RevisionList, Rejection> {
+        let (after, limit) = validate_page(query.after.as_deref(), query.limit)?;
+        let after_id = after
+            .map(|after| {
+                after.parse::<i64>().map_err(|_| {
+                    reject::custom(Error::ValidationError(
+                        "after".to_string(),
+                        Some(HashMap::new()),
+                    ))
+                })
+            })
+            .transpose()?;
+
+        let mut revisions = repo
+            .interact(move |ops| ops.history(&user_addr, &key, after_id, limit + 1))
+            .await?;
+
+        let next_cursor = if revisions.len() as i64 > limit {
+            revisions.truncate(limit as usize);
+            revisions.last().map(|r| encode_cursor(&r.id.to_string()))
+        } else {
+            None
+        };
+
+        Ok(RevisionList {
+            revisions: revisions.into_iter().map(Revision::from).collect(),
+            next_cursor,
+        })
+    }
+
+    pub(super) async fn restore_entry<R: Repo>(
+        key: String,
+        request: RestoreRequest,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<(Entry, i64), Rejection> {
+        let invalid = |reason: &str| {
+            reject::custom(Error::ValidationError(
+                key.clone(),
+                Some(HashMap::from([("reason".to_string(), reason.to_string())])),
+            ))
+        };
+        if request.revision_id.is_some() == request.at.is_some() {
+            return Err(invalid("exactly one of revision_id and at is required"));
+        }
+        let expires_at = expiry(&key, request.ttl_seconds, request.expires_at)?;
+
+        let result = repo
+            .transaction(move |ops| {
+                let old_entry = match (request.revision_id, request.at) {
+                    (Some(id), _) => ops
+                        .get_revision(&user_addr, &key, id)?
+                        .ok_or_else(|| Error::KeyNotFound(key.clone()))?
+                        .entry(),
+                    (None, Some(at)) => ops.get_at(&user_addr, &key, at)?,
+                    (None, None) => unreachable!(),
+                };
+                let old_entry = old_entry.ok_or_else(|| {
+                    Error::ValidationError(
+                        key.clone(),
+                        Some(HashMap::from([(
+                            "reason".to_string(),
+                            "there is no entry at this revision".to_string(),
+                        )])),
+                    )
+                })?;
+
+                let expires_at = match (expires_at, old_entry.expires_at) {
+                    (Some(expires_at), _) => Some(expires_at),
+                    (None, Some(expired_at)) if expired_at <= Utc::now() => {
+                        return Err(Error::ValidationError(
+                            key.clone(),
+                            Some(HashMap::from([(
+                                "reason".to_string(),
+                                "the revision has expired, a new ttl_seconds or expires_at is required"
+                                    .to_string(),
+                            )])),
+                        ))
+                    }
+                    (None, expires_at) => expires_at,
+                };
+                let restored = UserStorageEntry {
+                    expires_at,
+                    ..old_entry
+                };
+
+                let usage = usage_before(ops, &quota, &user_addr)?;
+                let version = ops.set(&restored)?;
+                check_quota(ops, &quota, &user_addr, &key, usage)?;
+
+                let entry = Entry::from(restored);
+                ops.publish(&user_addr, &[Change::updated(key, entry.clone(), version)])?;
+
+                Ok((entry, version))
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    pub(super) async fn set_single_entry<R: Repo>(
+        key: String,
+        precondition: Precondition,
+        expiry_query: ExpiryQuery,
+        entry: Entry,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<(Option<Entry>, i64), Rejection> {
+        validate_entry(&key, &entry)?;
+        let expires_at = expiry(&key, expiry_query.ttl_seconds, expiry_query.expires_at)?;
+        let result = repo
+            .transaction(move |ops| {
+                let new_entry = entry.clone();
+                let entry = UserStorageEntry {
+                    expires_at,
+                    ..UserStorageEntry::from((user_addr.clone(), key.clone(), entry))
+                };
+
+                let usage = usage_before(ops, &quota, &user_addr)?;
+
+                if precondition.is_absent() {
+                    let version = ops
+                        .insert(&entry)?
+                        .ok_or_else(|| Error::PreconditionFailed(key.clone()))?;
+                    check_quota(ops, &quota, &user_addr, &key, usage)?;
+                    ops.publish(&user_addr, &[Change::updated(key, new_entry, version)])?;
+                    return Ok((None, version));
+                }
+
+                let old_entry = if precondition.is_unconditional() {
+                    ops.get(&user_addr, &key)?
+                } else {
+                    ops.mget_for_update(&user_addr, &[&key])?.pop()
+                };
+                if !precondition.is_satisfied(old_entry.as_ref().map(|e| e.version)) {
+                    return Err(Error::PreconditionFailed(key));
+                }
+
+                let version = ops.set(&entry)?;
+                check_quota(ops, &quota, &user_addr, &key, usage)?;
+                ops.publish(&user_addr, &[Change::updated(key, new_entry, version)])?;
+
+                Ok((old_entry.map(Entry::from), version))
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    pub(super) async fn patch_single_entry<R: Repo>(
+        key: String,
+        precondition: Precondition,
+        content_type: String,
+        body: Bytes,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<(Entry, i64), Rejection> {
+        let patch = JsonPatch::parse(&content_type, &body)?;
+        let result = repo
+            .transaction(move |ops| {
+                // the document is patched here rather than in SQL, as neither RFC
+                // maps onto jsonb operators, so lock the row until the new one is written
+                let old_entry = ops
+                    .mget_for_update(&user_addr, &[&key])?
+                    .pop()
+                    .ok_or_else(|| Error::KeyNotFound(key.clone()))?;
+                if !precondition.is_satisfied(Some(old_entry.version)) {
+                    return Err(Error::PreconditionFailed(key));
+                }
+                if old_entry.entry_type != "json" {
+                    return Err(Error::ValidationError(
+                        key,
+                        Some(HashMap::from([
+                            ("expected_type".to_string(), "json".to_string()),
+                            ("actual_type".to_string(), old_entry.entry_type),
+                        ])),
+                    ));
+                }
+
+                let expires_at = old_entry.expires_at;
+                let mut doc = old_entry.entry_value_json.unwrap_or_default();
+                patch.apply(&key, &mut doc)?;
+
+                let entry = Entry::Json(doc);
+                validate_entry(&key, &entry)?;
+
+                let usage = usage_before(ops, &quota, &user_addr)?;
+                let version = ops.set(&UserStorageEntry {
+                    expires_at,
+                    ..UserStorageEntry::from((user_addr.clone(), key.clone(), entry.clone()))
+                })?;
+                check_quota(ops, &quota, &user_addr, &key, usage)?;
+                ops.publish(&user_addr, &[Change::updated(key, entry.clone(), version)])?;
+
+                Ok((entry, version))
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    pub(super) async fn incr_entry<R: Repo>(
+        key: String,
+        incr: IncrRequest,
+        user_addr: String,
+        repo: Arc<R>,
+        quota: Quota,
+    ) -> Result<(Entry, i64), Rejection> {
+        if let (Some(min), Some(max)) = (incr.min, incr.max) {
+            if min > max {
+                return Err(reject::custom(Error::ValidationError(
+                    "min".to_string(),
+                    Some(HashMap::from([
+                        ("min".to_string(), min.to_string()),
+                        ("max".to_string(), max.to_string()),
+                    ])),
+                )));
+            }
+        }
+
+        let entry = repo
+            .transaction(move |ops| {
+                let usage = usage_before(ops, &quota, &user_addr)?;
+                let new_entry = ops
+                    .incr(&user_addr, &key, incr.delta, incr.min, incr.max)?
+                    .ok_or_else(|| {
+                        Error::ValidationError(
+                            key.clone(),
+                            Some(HashMap::from([(
+                                "reason".to_string(),
+                                "entry is not an integer".to_string(),
+                            )])),
+                        )
+                    })?;
+                check_quota(ops, &quota, &user_addr, &key, usage)?;
+
+                let version = new_entry.version;
+                let entry = Entry::from(new_entry);
+                ops.publish(&user_addr, &[Change::updated(key, entry.clone(), version)])?;
+
+                Ok((entry, version))
+            })
+            .await?;
+
+        Ok(entry)
+    }
+
+    pub(super) async fn delete_single_entry<R: Repo>(
+        key: String,
+        user_addr: String,
+        repo: Arc<R>,
+    ) -> Result<Entry, Rejection> {
+        let ((old_entry, _), _) = get_single_entry(
+            key.clone(),
+            EntryQuery::default(),
+            user_addr.clone(),
+            repo.clone(),
+        )
+        .await?;
+
+        repo.transaction(move |ops| delete_and_publish(ops, &user_addr, &[key]))
+            .await?;
+
+        Ok(old_entry)
+    }
+
+    pub(super) async fn subscribe_changes<R: Repo>(
+        query: ChangesQuery,
+        last_event_id: Option<String>,
+        user_addr: String,
+        repo: Arc<R>,
+        feed: ChangeFeed,
+    ) -> Result<impl Stream<Item = Result<sse::Event, serde_json::Error>>, Rejection> {
+        let last_event_id = last_event_id
+            .map(|id| {
+                id.parse::<i64>().map_err(|_| {
+                    Error::ValidationError("Last-Event-ID".to_string(), Some(HashMap::new()))
+                })
+            })
+            .transpose()?;
+
+        // subscribe before looking up the last change, so that nothing is missed in between
+        let notifications = feed.subscribe();
+        let after_id = match last_event_id {
+            Some(id) => id,
+            None => {
+                let user_addr = user_addr.clone();
+                repo.interact(move |ops| ops.last_change_id(&user_addr))
+                    .await?
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(CHANGES_BUFFER_SIZE);
+        tokio::spawn(changes::follow(
+            repo,
+            notifications,
+            user_addr,
+            query.prefix,
+            after_id,
+            tx,
+        ));
+
+        Ok(ReceiverStream::new(rx).map(|event| match event {
+            changes::Event::Changed(id, change) => sse::Event::default()
+                .id(id.to_string())
+                .event("change")
+                .json_data(change),
+            // not a recorded change, so it has no id to resume from
+            changes::Event::Erased => sse::Event::default()
+                .event("erased")
+                .json_data(serde_json::json!({})),
+        }))
+    }
+
+    /// The usage before the writes of a transaction, `None` if there is no quota to check.
+    fn usage_before<O: RepoOperations>(
+        ops: &mut O,
+        quota: &Quota,
+        user_addr: &String,
+    ) -> Result<Option<UserStorageUsage>, Error> {
+        if quota.max_entries.is_none() && quota.max_bytes.is_none() {
+            return Ok(None);
+        }
+        ops.usage(user_addr).map(Some)
+    }
+
+    /// Fails if the writes made in the transaction took the usage over the quota.
+    /// Writes that don't increase the usage are allowed, so that a user over the quota
+    /// can still remove or shrink entries.
+    fn check_quota<O: RepoOperations>(
+        ops: &mut O,
+        quota: &Quota,
+        user_addr: &String,
+        field: &str,
+        usage_before: Option<UserStorageUsage>,
+    ) -> Result<(), Error> {
+        let before = match usage_before {
+            Some(before) => before,
+            None => return Ok(()),
+        };
+        let after = ops.usage(user_addr)?;
+
+        let exceeds = |max: Option<i64>, before: i64, after: i64| match max {
+            Some(max) => after > max && after > before,
+            None => false,
+        };
+        if !exceeds(quota.max_entries, before.entry_count, after.entry_count)
+            && !exceeds(quota.max_bytes, before.total_bytes, after.total_bytes)
+        {
+            return Ok(());
+        }
+
+        let mut details = HashMap::from([
+            ("entry_count".to_string(), after.entry_count.to_string()),
+            ("total_bytes".to_string(), after.total_bytes.to_string()),
+        ]);
+        if let Some(max_entries) = quota.max_entries {
+            details.insert("max_entries".to_string(), max_entries.to_string());
+        }
+        if let Some(max_bytes) = quota.max_bytes {
+            details.insert("max_bytes".to_string(), max_bytes.to_string());
+        }
+        Err(Error::ValidationError(field.to_string(), Some(details)))
+    }
+
+    /// Serializes a page of exported entries, the first page of a JSON document
+    /// is the only one not to start with a separator.
+    fn export_chunk(
+        format: ExportFormat,
+        is_first_page: bool,
+        page: Vec<UserStorageEntry>,
+    ) -> Result<Bytes, Error> {
+        let mut chunk = vec![];
+        for (i, entry) in page.into_iter().enumerate() {
+            if let ExportFormat::Json = format {
+                if !is_first_page || i > 0 {
+                    chunk.push(b',');
+                }
+            }
+            serde_json::to_writer(&mut chunk, &KeyEntryPair::from(entry))?;
+            if let ExportFormat::Ndjson = format {
+                chunk.push(b'\n');
+            }
+        }
+        Ok(Bytes::from(chunk))
+    }
+
+    fn delete_and_publish<O: RepoOperations>(
+        ops: &mut O,
+        user_addr: &String,
+        keys: &[String],
+    ) -> Result<(), Error> {
+        let changes = ops
+            .mdel(user_addr, keys)?
+            .into_iter()
+            .map(|(key, version)| Change::deleted(key, version))
+            .collect::<Vec<_>>();
+        if !changes.is_empty() {
+            ops.publish(user_addr, &changes)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_entry(key: &str, entry: &Entry) -> Result<(), Error> {
+    let rej = |size: u64| {
+        Error::ValidationError(
+            key.to_string(),
+            Some(HashMap::from([
+                ("actual_size".to_string(), size.to_string()),
+                ("max_size".to_string(), MAX_JSON_PAYLOAD_SIZE.to_string()),
+            ])),
+        )
+    };
+
+    match entry {
+        Entry::Json(d) => {
+            let payload_size = serde_json::to_vec(d).unwrap().len() as u64;
+            if payload_size > MAX_JSON_PAYLOAD_SIZE {
+                return Err(rej(payload_size));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Parses a JSON path made of member and index accessors only, e.g. `$.layout.columns[0]`,
+/// into the path elements understood by the jsonb `#>` operator.
+fn parse_json_path(path: &str) -> Result<Vec<String>, Error> {
+    let invalid = || {
+        Error::ValidationError(
+            "path".to_string(),
+            Some(HashMap::from([("path".to_string(), path.to_string())])),
+        )
+    };
+
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut elements = vec![];
+    while !rest.is_empty() {
+        if let Some(member) = rest.strip_prefix('.') {
+            let end = member.find(['.', '[']).unwrap_or(member.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            elements.push(member[..end].to_string());
+            rest = &member[end..];
+        } else if let Some(index) = rest.strip_prefix('[') {
+            let end = index.find(']').ok_or_else(invalid)?;
+            let element = &index[..end];
+            match element.strip_prefix('"').and_then(|e| e.strip_suffix('"')) {
+                Some(member) => elements.push(member.to_string()),
+                None if element.parse::<u32>().is_ok() => elements.push(element.to_string()),
+                None => return Err(invalid()),
+            }
+            rest = &index[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    Ok(elements)
+}
+
+/// Converts an entry returned by `RepoOperations::get_path`,
+/// which is `None` if there was nothing at the requested path.
+fn projected(entry: UserStorageEntry) -> Result<Option<Entry>, Error> {
+    if entry.entry_type != "json" {
+        return Err(Error::ValidationError(
+            entry.key,
+            Some(HashMap::from([
+                ("expected_type".to_string(), "json".to_string()),
+                ("actual_type".to_string(), entry.entry_type),
+            ])),
+        ));
+    }
+
+    Ok(entry.entry_value_json.is_some().then(|| Entry::from(entry)))
+}
+
+/// Parses the entries to import, given either as a `KeyEntryList` document
+/// or as `KeyEntryPair` lines of NDJSON, as produced by the export.
+fn parse_import(content_type: Option<&str>, body: &[u8]) -> Result<Vec<KeyEntryPair>, Error> {
+    let invalid = |e: serde_json::Error| {
+        Error::ValidationError(
+            "body".to_string(),
+            Some(HashMap::from([("reason".to_string(), e.to_string())])),
+        )
+    };
+
+    let media_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .unwrap_or("application/json")
+        .trim();
+    match media_type {
+        "application/json" => serde_json::from_slice::<KeyEntryList>(body)
+            .map(|list| list.entries)
+            .map_err(invalid),
+        NDJSON_MEDIA_TYPE => body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).map_err(invalid))
+            .collect(),
+        _ => Err(Error::UnsupportedMediaType(media_type.to_string())),
+    }
+}
+
+/// A patch for `Entry::Json` documents, chosen by the request `Content-Type`.
+enum JsonPatch {
+    /// `application/merge-patch+json`, RFC 7396
+    Merge(serde_json::Value),
+    /// `application/json-patch+json`, RFC 6902
+    Json(json_patch::Patch),
+}
+
+impl JsonPatch {
+    fn parse(content_type: &str, body: &[u8]) -> Result<Self, Error> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let patch = match media_type {
+            "application/merge-patch+json" => serde_json::from_slice(body).map(JsonPatch::Merge),
+            "application/json-patch+json" => serde_json::from_slice(body).map(JsonPatch::Json),
+            _ => return Err(Error::UnsupportedMediaType(media_type.to_string())),
+        };
+        patch.map_err(|e| {
+            Error::ValidationError(
+                "body".to_string(),
+                Some(HashMap::from([("reason".to_string(), e.to_string())])),
+            )
+        })
+    }
+
+    fn apply(&self, key: &str, doc: &mut serde_json::Value) -> Result<(), Error> {
+        match self {
+            JsonPatch::Merge(patch) => json_patch::merge(doc, patch),
+            JsonPatch::Json(patch) => json_patch::patch(doc, patch).map_err(|e| {
+                Error::ValidationError(
+                    key.to_string(),
+                    Some(HashMap::from([("reason".to_string(), e.to_string())])),
+                )
+            })?,
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the expiration time of an entry, given either as a TTL or as an exact timestamp.
+fn expiry(
+    key: &str,
+    ttl_seconds: Option<u32>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Option<DateTime<Utc>>, Rejection> {
+    match (ttl_seconds, expires_at) {
+        (Some(_), Some(_)) => Err(reject::custom(Error::ValidationError(
+            key.to_string(),
+            Some(HashMap::from([(
+                "reason".to_string(),
+                "ttl_seconds and expires_at are mutually exclusive".to_string(),
+            )])),
+        ))),
+        (Some(ttl), None) => Ok(Some(Utc::now() + Duration::seconds(ttl as i64))),
+        (None, expires_at) => Ok(expires_at),
+    }
+}
+
+/// Condition on the current version of an entry, given by the `If-Match` / `If-None-Match` headers.
+/// Both have to hold if both are given.
+#[derive(Default)]
+struct Precondition {
+    if_match: Option<EntityTags>,
+    if_none_match: Option<EntityTags>,
+}
+
+/// Value of an `If-Match` / `If-None-Match` header, the ETags which can't be parsed never match.
+enum EntityTags {
+    /// `*`
+    Any,
+    /// `"<version>", ...`
+    Versions(Vec<i64>),
+}
+
+impl Precondition {
+    fn from_headers(if_match: Option<String>, if_none_match: Option<String>) -> Self {
+        Precondition {
+            if_match: if_match.as_deref().map(EntityTags::parse),
+            if_none_match: if_none_match.as_deref().map(EntityTags::parse),
+        }
+    }
+
+    fn is_unconditional(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    /// `If-None-Match: *` alone, the entry is to be created only.
+    fn is_absent(&self) -> bool {
+        self.if_match.is_none() && matches!(self.if_none_match, Some(EntityTags::Any))
+    }
+
+    fn is_satisfied(&self, current_version: Option<i64>) -> bool {
+        let if_match = match &self.if_match {
+            Some(tags) => tags.matches(current_version),
+            None => true,
+        };
+        let if_none_match = match &self.if_none_match {
+            Some(tags) => !tags.matches(current_version),
+            None => true,
+        };
+        if_match && if_none_match
+    }
+}
+
+impl EntityTags {
+    fn parse(header: &str) -> Self {
+        if header.trim() == "*" {
+            EntityTags::Any
+        } else {
+            EntityTags::Versions(header.split(',').filter_map(parse_etag).collect())
+        }
+    }
+
+    /// A missing entry matches nothing, not even `*`.
+    fn matches(&self, current_version: Option<i64>) -> bool {
+        match (self, current_version) {
+            (_, None) => false,
+            (EntityTags::Any, Some(_)) => true,
+            (EntityTags::Versions(versions), Some(version)) => versions.contains(&version),
+        }
+    }
+}
+
+fn etag(version: i64) -> String {
+    format!("\"{version}\"")
+}
+
+fn parse_etag(etag: &str) -> Option<i64> {
+    etag.trim()
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .ok()
+}
+
+/// Matches the requests to erase all the data of a user, leaving the ones
+/// without `all=true` to the routes deleting the given keys.
+fn erase_all_query() -> BoxedFilter<(EraseQuery,)> {
+    serde_qs::warp::query::<EraseQuery>(serde_qs::Config::new(5, false))
+        .and_then(|query: EraseQuery| async move {
+            if query.all {
+                Ok(query)
+            } else {
+                Err(reject::not_found())
+            }
+        })
+        .boxed()
+}
+
+/// Matches the requests to delete the given keys, leaving the ones with `all=true`
+/// to the erasure, so that an erasure with a wrong confirmation is rejected by it
+/// rather than deleting the keys in the body.
+fn delete_keys_query() -> BoxedFilter<()> {
+    serde_qs::warp::query::<EraseQuery>(serde_qs::Config::new(5, false))
+        .map(|query: EraseQuery| query.all)
+        .or(warp::any().map(|| false))
+        .unify()
+        .and_then(|all: bool| async move {
+            if all {
+                Err(reject::not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Decodes the `after` cursor and checks the requested page size,
+/// falling back to the default page size if no limit is given.
+fn validate_page(
+    after: Option<&str>,
+    limit: Option<u32>,
+) -> Result<(Option<String>, i64), Rejection> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_PAGE_LIMIT {
+        return Err(reject::custom(Error::ValidationError(
+            "limit".to_string(),
+            Some(HashMap::from([
+                ("actual_limit".to_string(), limit.to_string()),
+                ("max_limit".to_string(), MAX_PAGE_LIMIT.to_string()),
+            ])),
+        )));
+    }
+
+    let after = after
+        .map(|cursor| {
+            decode_cursor(cursor).ok_or_else(|| {
+                reject::custom(Error::ValidationError(
+                    "after".to_string(),
+                    Some(HashMap::new()),
+                ))
+            })
+        })
+        .transpose()?;
+
+    Ok((after, limit as i64))
+}
+
+/// Turns a page fetched with `limit + 1` rows into a listing,
+/// setting the cursor only if there is something left after this page.
+fn into_listing(
+    mut raw_entries: Vec<UserStorageEntry>,
+    limit: i64,
+    with_entries: bool,
+) -> KeyListing {
+    let next_cursor = if raw_entries.len() as i64 > limit {
+        raw_entries.truncate(limit as usize);
+        raw_entries.last().map(|e| encode_cursor(&e.key))
+    } else {
+        None
+    };
+
+    let keys = raw_entries.iter().map(|e| e.key.clone()).collect();
+    let entries = with_entries.then(|| raw_entries.into_iter().map(Entry::from).collect());
+
+    KeyListing {
+        keys,
+        entries,
+        next_cursor,
+    }
+}
+
+fn encode_cursor(key: &str) -> String {
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+async fn recover_rate_limited(rej: Rejection) -> Result<impl Reply, Rejection> {
+    match rej.find::<Error>() {
+        Some(Error::TooManyRequests(retry_after)) => {
+            // Retry-After is in whole seconds, so round up not to have the client retry too early
+            let retry_after = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+            Ok(with_header(
+                error_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests", None),
+                RETRY_AFTER,
+                retry_after.max(1).to_string(),
+            ))
+        }
+        _ => Err(rej),
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    message: &str,
+    details: Option<HashMap<String, String>>,
+) -> ErrorListResponse {
+    ErrorListResponse {
+        http_status: status,
+        errors: vec![ErrorResponse {
+            code: ERROR_CODES_PREFIX as u32 * 10000 + status.as_u16() as u32,
+            message: message.to_owned(),
+            details,
+        }],
+    }
+}
+
+fn to_json<T: Serialize>(data: T) -> Json {
+    json(&data)
+}
+
+fn versioned_entry((entry, version): (Entry, i64)) -> impl Reply {
+    with_header(to_json(entry), ETAG, etag(version))
+}
+
+/// Marks the reply as possibly stale if any of the entries in it were read from the cache
+/// alone, the storage being unavailable, see `repo::cache`.
+fn marked_stale(reply: impl Reply, is_stale: bool) -> Response {
+    if !is_stale {
+        return reply.into_response();
+    }
+    let reply = with_header(reply, WARNING, "110 - \"Response is Stale\"");
+    with_header(reply, "X-Stale", "true").into_response()
+}
+
+/// The old entry if it was overwritten, `201 Created` otherwise.
+fn set_entry_result((old_entry, version): (Option<Entry>, i64)) -> impl Reply {
+    let response = match old_entry {
+        Some(old) => to_json(old).into_response(),
+        None => with_status(reply(), StatusCode::CREATED).into_response(),
+    };
+    with_header(response, ETAG, etag(version))
+}
@@ -0,0 +1,224 @@
+//! Token bucket rate limiting of the requests, reads (`GET`, `HEAD`) and writes having
+//! separate budgets. A request is limited once authenticated, by the user it's made on behalf of
+//! and by the client IP if configured. The client IP is the address of the connection, or
+//! the one appended to `X-Forwarded-For` by the outermost of the configured trusted proxies.
+
+use crate::config::api::{Budget, RateLimit};
+use crate::error::Error;
+use crate::models::UserAddress;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::{filters::BoxedFilter, http::Method, reject, Filter, Rejection};
+
+/// The number of buckets kept, the least recently used ones dropped beyond it.
+/// A dropped bucket starts over full, as the least recently used ones mostly are anyway.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Storage of the token buckets. The in-process one limits each replica on its own,
+/// a shared one makes the replicas enforce common limits.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync + 'static {
+    /// Takes a token from each of the buckets if all of them have one, otherwise takes none
+    /// and returns how long to wait for the emptiest one.
+    async fn acquire(&self, keys: &[String], budget: Budget) -> Result<(), Duration>;
+}
+
+#[derive(Default)]
+pub struct InMemoryBuckets {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// Keys by the sequence number of their last use, the least recent first
+    recency: BTreeMap<u64, String>,
+    next_use: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    last_use: u64,
+}
+
+impl Buckets {
+    /// The bucket refilled for the time passed since it was last used.
+    fn refill(&mut self, key: &str, budget: Budget, now: Instant) -> &mut Bucket {
+        let last_use = self.next_use;
+        self.next_use += 1;
+
+        match self.by_key.get_mut(key) {
+            Some(bucket) => {
+                self.recency.remove(&bucket.last_use);
+                bucket.last_use = last_use;
+            }
+            None => {
+                let bucket = Bucket {
+                    tokens: budget.burst as f64,
+                    updated_at: now,
+                    last_use,
+                };
+                self.by_key.insert(key.to_string(), bucket);
+            }
+        }
+        self.recency.insert(last_use, key.to_string());
+
+        while self.by_key.len() > MAX_BUCKETS {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.by_key.remove(&key);
+            }
+        }
+
+        let bucket = self
+            .by_key
+            .get_mut(key)
+            .expect("the bucket is the most recent one");
+        let refilled = now.duration_since(bucket.updated_at).as_secs_f64() * budget.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(budget.burst as f64);
+        bucket.updated_at = now;
+        bucket
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBuckets {
+    async fn acquire(&self, keys: &[String], budget: Budget) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = buckets.refill(key, budget, now);
+            if bucket.tokens < 1.0 {
+                let missing = (1.0 - bucket.tokens) / budget.per_second;
+                wait = wait.max(Duration::from_secs_f64(missing));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for key in keys {
+            if let Some(bucket) = buckets.by_key.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rate limits applied to the requests authenticated by the wrapped filters.
+#[derive(Clone)]
+pub(super) struct Limiter {
+    config: RateLimit,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl Limiter {
+    pub(super) fn new(config: RateLimit, backend: Arc<dyn RateLimitBackend>) -> Self {
+        Limiter { config, backend }
+    }
+
+    /// Limits the requests of the user resolved by `user_addr`.
+    pub(super) fn user(
+        &self,
+        user_addr: BoxedFilter<(UserAddress,)>,
+    ) -> BoxedFilter<(UserAddress,)> {
+        let limiter = self.clone();
+        user_addr
+            .and(warp::method())
+            .and(client_ip(self.config.trusted_proxies))
+            .and_then(move |user_addr: UserAddress, method, ip| {
+                let limiter = limiter.clone();
+                async move {
+                    limiter.acquire(&method, &user_addr, ip).await?;
+                    Ok::<_, Rejection>(user_addr)
+                }
+            })
+            .boxed()
+    }
+
+    /// Limits the requests of the user resolved by `body` along with the request body.
+    pub(super) fn body<T>(
+        &self,
+        body: BoxedFilter<(T, UserAddress)>,
+    ) -> BoxedFilter<(T, UserAddress)>
+    where
+        T: Send + 'static,
+    {
+        let limiter = self.clone();
+        body.and(warp::method())
+            .and(client_ip(self.config.trusted_proxies))
+            .and_then(move |body: T, user_addr: UserAddress, method, ip| {
+                let limiter = limiter.clone();
+                async move {
+                    limiter.acquire(&method, &user_addr, ip).await?;
+                    Ok::<_, Rejection>((body, user_addr))
+                }
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// The client IP the requests are limited by, for the messages of a WebSocket
+    /// to be limited after the upgrade request.
+    pub(super) fn client_ip(&self) -> BoxedFilter<(Option<IpAddr>,)> {
+        client_ip(self.config.trusted_proxies).boxed()
+    }
+
+    /// Takes a token from the budgets of the user and the client IP, the read ones for
+    /// `GET` and `HEAD` and the write ones for any other method.
+    pub(super) async fn acquire(
+        &self,
+        method: &Method,
+        user_addr: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Rejection> {
+        let (kind, budget) = match *method {
+            Method::GET | Method::HEAD => ("read", self.config.read),
+            _ => ("write", self.config.write),
+        };
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        let mut keys = vec![format!("{kind}:user:{user_addr}")];
+        if let (true, Some(ip)) = (self.config.by_ip, ip) {
+            keys.push(format!("{kind}:ip:{ip}"));
+        }
+        self.backend
+            .acquire(&keys, budget)
+            .await
+            .map_err(|retry_after| reject::custom(Error::TooManyRequests(retry_after)))
+    }
+}
+
+fn client_ip(
+    trusted_proxies: usize,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("X-Forwarded-For")
+        .and(warp::addr::remote())
+        .map(
+            move |forwarded_for: Option<String>, remote: Option<SocketAddr>| {
+                // each proxy appends the address it got the request from, so the entries
+                // left of the ones appended by the trusted proxies may be made up by the client
+                let forwarded = match trusted_proxies {
+                    0 => None,
+                    n => forwarded_for.and_then(|ips| {
+                        ips.rsplit(',')
+                            .nth(n - 1)
+                            .and_then(|ip| ip.trim().parse().ok())
+                    }),
+                };
+                forwarded.or_else(|| remote.map(|addr| addr.ip()))
+            },
+        )
+}
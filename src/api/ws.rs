@@ -0,0 +1,245 @@
+//! WebSocket interface: a client subscribes to the changes of its own keys
+//! and may get and set entries over the same socket.
+
+use super::{controllers, rate_limit::Limiter, CHANGES_BUFFER_SIZE};
+use crate::changes::{self, ChangeFeed, Event};
+use crate::config::api::Quota;
+use crate::error::Error;
+use crate::models::dto::{
+    KeyEntryList, KeyList, NullableEntryList, Subscription, WsRequest, WsResponse,
+};
+use crate::models::UserAddress;
+use crate::repo::{Repo, RepoOperations};
+use futures::{SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use warp::http::Method;
+use warp::ws::{Message, WebSocket};
+use warp::Rejection;
+use wavesexchange_log::error;
+
+/// The client of a socket, whose `get` and `set` requests are rate limited
+/// the same as the HTTP ones.
+pub(super) struct Client {
+    pub(super) user_addr: UserAddress,
+    pub(super) ip: Option<IpAddr>,
+    pub(super) limiter: Limiter,
+}
+
+pub(super) async fn serve<R: Repo>(
+    socket: WebSocket,
+    client: Client,
+    repo: Arc<R>,
+    feed: ChangeFeed,
+    quota: Quota,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let user_addr = client.user_addr.clone();
+
+    // subscribe before looking up the last change, so that nothing is missed in between
+    let notifications = feed.subscribe();
+    let last_change_id = {
+        let user_addr = user_addr.clone();
+        repo.interact(move |ops| ops.last_change_id(&user_addr))
+            .await
+    };
+    let after_id = match last_change_id {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to read the last change of {}: {}", user_addr, e);
+            let _ = sink.close().await;
+            return;
+        }
+    };
+
+    let (tx, mut changes) = mpsc::channel(CHANGES_BUFFER_SIZE);
+    tokio::spawn(changes::follow(
+        repo.clone(),
+        notifications,
+        user_addr.clone(),
+        String::new(),
+        after_id,
+        tx,
+    ));
+
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        let response = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => match message.to_str() {
+                    Ok(text) => handle(text, &mut subscriptions, &client, &repo, quota).await,
+                    // binary, ping and pong messages
+                    Err(_) => continue,
+                },
+                Some(Err(e)) => {
+                    error!("WebSocket of {} failed: {}", user_addr, e);
+                    break;
+                }
+                None => break,
+            },
+            event = changes.recv() => match event {
+                Some(Event::Changed(id, change)) if subscriptions.matches(&change.key) => {
+                    Some(WsResponse::Change { id, change })
+                }
+                Some(Event::Erased) if !subscriptions.is_empty() => Some(WsResponse::Erased),
+                Some(_) => continue,
+                // following the changes failed, the client has to reconnect
+                None => break,
+            },
+        };
+
+        if let Some(response) = response {
+            let text = serde_json::to_string(&response).expect("response is serializable");
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+async fn handle<R: Repo>(
+    text: &str,
+    subscriptions: &mut Subscriptions,
+    client: &Client,
+    repo: &Arc<R>,
+    quota: Quota,
+) -> Option<WsResponse> {
+    let request = match serde_json::from_str::<WsRequest>(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(WsResponse::Error {
+                request_id: None,
+                message: "Invalid Message".to_owned(),
+                details: Some(HashMap::from([("reason".to_owned(), e.to_string())])),
+            })
+        }
+    };
+
+    match request {
+        WsRequest::Subscribe(subscription) => {
+            subscriptions.add(subscription);
+            None
+        }
+        WsRequest::Unsubscribe(subscription) => {
+            subscriptions.remove(subscription);
+            None
+        }
+        WsRequest::Get { request_id, keys } => {
+            let result = match client.acquire(&Method::GET).await {
+                Ok(()) => controllers::get_entries(
+                    KeyList { keys },
+                    client.user_addr.clone(),
+                    repo.clone(),
+                )
+                .await
+                .map(|(entries, _)| entries),
+                Err(rejection) => Err(rejection),
+            };
+            Some(respond(request_id, result))
+        }
+        WsRequest::Set {
+            request_id,
+            entries,
+        } => {
+            let result = match client.acquire(&Method::PUT).await {
+                Ok(()) => {
+                    controllers::set_entries(
+                        KeyEntryList { entries },
+                        client.user_addr.clone(),
+                        repo.clone(),
+                        quota,
+                    )
+                    .await
+                }
+                Err(rejection) => Err(rejection),
+            };
+            Some(respond(request_id, result))
+        }
+    }
+}
+
+fn respond(request_id: String, result: Result<NullableEntryList, Rejection>) -> WsResponse {
+    let rejection = match result {
+        Ok(list) => {
+            return WsResponse::Entries {
+                request_id,
+                entries: list.entries,
+            }
+        }
+        Err(rejection) => rejection,
+    };
+
+    let (message, details) = match rejection.find::<Error>() {
+        Some(Error::ValidationError(field, details)) => {
+            let mut details = details.clone().unwrap_or_default();
+            details.insert("parameter".to_owned(), field.to_owned());
+            ("Invalid Parameter", Some(details))
+        }
+        Some(Error::PreconditionFailed(key)) => (
+            "Precondition Failed",
+            Some(HashMap::from([("key".to_owned(), key.to_owned())])),
+        ),
+        Some(Error::TooManyRequests(retry_after)) => (
+            "Too Many Requests",
+            Some(HashMap::from([(
+                "retry_after_ms".to_owned(),
+                retry_after.as_millis().max(1).to_string(),
+            )])),
+        ),
+        _ => {
+            error!("{:?}", rejection);
+            ("Internal Server Error", None)
+        }
+    };
+
+    WsResponse::Error {
+        request_id: Some(request_id),
+        message: message.to_owned(),
+        details,
+    }
+}
+
+impl Client {
+    async fn acquire(&self, method: &Method) -> Result<(), Rejection> {
+        self.limiter.acquire(method, &self.user_addr, self.ip).await
+    }
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    keys: HashSet<String>,
+    prefixes: HashSet<String>,
+}
+
+impl Subscriptions {
+    fn add(&mut self, subscription: Subscription) {
+        self.keys.extend(subscription.keys);
+        self.prefixes.extend(subscription.prefixes);
+    }
+
+    fn remove(&mut self, subscription: Subscription) {
+        for key in subscription.keys {
+            self.keys.remove(&key);
+        }
+        for prefix in subscription.prefixes {
+            self.prefixes.remove(&prefix);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.prefixes.is_empty()
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.keys.contains(key)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
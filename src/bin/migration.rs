@@ -1,23 +1,44 @@
+use diesel::backend::Backend;
 use diesel::migration::Migration;
 use diesel::{migration, pg::PgConnection, Connection};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
-use lib::{config, db::generate_postgres_url};
+use lib::{config, config::storage, db::generate_postgres_url};
 
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
 fn main() -> anyhow::Result<()> {
     let action = action::parse_command_line()?;
-    let dbconfig = config::postgres::load()?;
-    let conn = PgConnection::establish(&generate_postgres_url(&dbconfig))?;
-    run(action, conn).map_err(|e| anyhow::anyhow!(e))
+    match config::storage::load()? {
+        storage::Config::Postgres(dbconfig) => {
+            let mut conn = PgConnection::establish(&generate_postgres_url(&dbconfig))?;
+            run(action, &mut conn, MIGRATIONS)
+        }
+        #[cfg(feature = "sqlite")]
+        storage::Config::Sqlite { path } => {
+            let mut conn = diesel::sqlite::SqliteConnection::establish(&path)?;
+            run(action, &mut conn, SQLITE_MIGRATIONS)
+        }
+        storage::Config::Memory => {
+            println!("Nothing to migrate, the memory storage starts empty.");
+            Ok(())
+        }
+    }
+    .map_err(|e| anyhow::anyhow!(e))
 }
 
-fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()> {
+fn run<DB: Backend>(
+    action: action::Action,
+    conn: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> migration::Result<()> {
     use action::Action::*;
     match action {
         ListPending => {
-            let list = conn.pending_migrations(MIGRATIONS)?;
+            let list = conn.pending_migrations(migrations)?;
             if list.is_empty() {
                 println!("No pending migrations.");
             }
@@ -26,7 +47,7 @@ fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()>
             }
         }
         MigrateUp => {
-            let list = conn.run_pending_migrations(MIGRATIONS)?;
+            let list = conn.run_pending_migrations(migrations)?;
             if list.is_empty() {
                 println!("No pending migrations.");
             }
@@ -35,7 +56,7 @@ fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()>
             }
         }
         MigrateDown => {
-            let mig = conn.revert_last_migration(MIGRATIONS)?;
+            let mig = conn.revert_last_migration(migrations)?;
             println!("Reverted migration: {}", mig);
         }
     }
@@ -1,7 +1,11 @@
 #[macro_use]
 extern crate wavesexchange_log;
 
-use lib::{api, config, db, error::Error, repo};
+use lib::api::rate_limit::InMemoryBuckets;
+use lib::config::{self, storage};
+use lib::repo::{self, Repo, RepoOperations};
+use lib::{api, changes::ChangeFeed, db, error::Error, reaper};
+use std::sync::Arc;
 use wavesexchange_repos::circuit_breaker::CircuitBreaker;
 
 #[tokio::main]
@@ -10,12 +14,59 @@ async fn main() -> Result<(), Error> {
 
     info!("Starting user-storage service with config: {:?}", config);
 
-    let cbrk = CircuitBreaker::builder_from_cfg(&config.cb)
-        .with_init_fn(move || db::async_pool(&config.pg))
-        .build()
-        .unwrap();
-    let storage_repo = repo::postgres::new(cbrk);
+    match config.storage.clone() {
+        storage::Config::Postgres(pg) => {
+            let changes = ChangeFeed::listen(&pg);
 
-    api::start(config.api.port, config.api.metrics_port, storage_repo).await;
+            let cbrk = CircuitBreaker::builder_from_cfg(&config.cb)
+                .with_init_fn(move || db::async_pool(&pg))
+                .build()
+                .unwrap();
+            let storage_repo = repo::postgres::new(cbrk);
+
+            run(storage_repo, changes, config).await;
+        }
+        #[cfg(feature = "sqlite")]
+        storage::Config::Sqlite { path } => {
+            let changes = ChangeFeed::new();
+            let storage_repo = repo::sqlite::new(&path, changes.clone())?;
+
+            run(storage_repo, changes, config).await;
+        }
+        storage::Config::Memory => {
+            let changes = ChangeFeed::new();
+            let storage_repo = repo::memory::new(changes.clone());
+
+            run(storage_repo, changes, config).await;
+        }
+    }
     Ok(())
 }
+
+async fn run<R, O>(storage_repo: R, changes: ChangeFeed, config: config::Config)
+where
+    // the cache lends the operations of the wrapped repo on to the interactions
+    R: for<'a> Repo<Operations<'a> = O>,
+    O: RepoOperations + 'static,
+{
+    match config.cache {
+        Some(cache_config) => {
+            let storage_repo = repo::cache::new(storage_repo, &cache_config, &changes);
+            serve(Arc::new(storage_repo), changes, config.api, config.reaper).await;
+        }
+        None => serve(Arc::new(storage_repo), changes, config.api, config.reaper).await,
+    }
+}
+
+async fn serve<R: Repo>(
+    storage_repo: Arc<R>,
+    changes: ChangeFeed,
+    api_config: config::api::Config,
+    reaper_config: config::reaper::Config,
+) {
+    tokio::spawn(reaper::run(storage_repo.clone(), reaper_config));
+
+    let rate_limiter = Arc::new(InMemoryBuckets::default());
+
+    api::start(api_config, storage_repo, changes, rate_limiter).await;
+}
@@ -0,0 +1,206 @@
+use crate::config::postgres::Config;
+use crate::db::generate_postgres_url;
+use crate::models::{dto::Change, UserAddress};
+use crate::repo::{Repo, RepoOperations};
+use futures::future::poll_fn;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_postgres::{AsyncMessage, NoTls};
+use wavesexchange_log::{error, info};
+
+/// Postgres channel the write paths notify on, with the user address as payload.
+pub const CHANNEL: &str = "user_storage_changes";
+/// Postgres channel the erasures notify on, with the user address as payload.
+pub const ERASURES_CHANNEL: &str = "user_storage_erasures";
+
+const NOTIFICATIONS_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CHANGES_BATCH_SIZE: i64 = 100;
+
+/// Tells the subscribers which users have new changes recorded.
+///
+/// Notifications carry no changes themselves: subscribers read them from the repo,
+/// so a missed or lagged notification only delays the delivery.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    notifications: broadcast::Sender<Notification>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    /// The user has new changes recorded
+    Changed(UserAddress),
+    /// All the user's data has been erased, with no changes recorded for it
+    Erased(UserAddress),
+    /// Notifications may have been missed, e.g. while reconnecting to Postgres,
+    /// so any user may have new changes
+    Reset,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        let (notifications, _) = broadcast::channel(NOTIFICATIONS_CAPACITY);
+        ChangeFeed { notifications }
+    }
+
+    /// Creates a feed fed by Postgres `LISTEN` on a dedicated connection.
+    pub fn listen(config: &Config) -> Self {
+        let feed = Self::new();
+        tokio::spawn(listen(generate_postgres_url(config), feed.clone()));
+        feed
+    }
+
+    pub fn notify(&self, user_addr: UserAddress) {
+        // no receivers is not an error, there is just nobody to notify
+        let _ = self.notifications.send(Notification::Changed(user_addr));
+    }
+
+    pub fn erased(&self, user_addr: UserAddress) {
+        let _ = self.notifications.send(Notification::Erased(user_addr));
+    }
+
+    /// Tells the subscribers that the notifications may have been missed.
+    pub fn reset(&self) {
+        let _ = self.notifications.send(Notification::Reset);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.notifications.subscribe()
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a follower of the user's changes is sent.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Changed(i64, Change),
+    /// All the user's entries are gone, their deletions are not sent one by one
+    Erased,
+}
+
+/// Sends the user's changes of the keys starting with `prefix` made after the `after_id` one,
+/// first catching up with the recorded ones and then following the notifications.
+///
+/// Runs until the receiving side is dropped or reading the changes fails,
+/// in which case the subscriber is expected to resume from the last received id.
+pub async fn follow<R: Repo>(
+    repo: Arc<R>,
+    mut notifications: broadcast::Receiver<Notification>,
+    user_addr: UserAddress,
+    prefix: String,
+    mut after_id: i64,
+    changes: mpsc::Sender<Event>,
+) {
+    loop {
+        let batch = {
+            let (user_addr, prefix) = (user_addr.clone(), prefix.clone());
+            repo.interact(move |ops| {
+                ops.changes_since(&user_addr, &prefix, after_id, CHANGES_BATCH_SIZE)
+            })
+            .await
+        };
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Failed to read changes of {}: {}", user_addr, e);
+                return;
+            }
+        };
+
+        let is_full_batch = batch.len() as i64 == CHANGES_BATCH_SIZE;
+        for change in batch {
+            after_id = change.id;
+            let change = match Change::try_from(change) {
+                Ok(change) => change,
+                Err(e) => {
+                    // it would be just as malformed on every retry
+                    error!(
+                        "Skipping malformed change {} of {}: {}",
+                        after_id, user_addr, e
+                    );
+                    continue;
+                }
+            };
+            if changes
+                .send(Event::Changed(after_id, change))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        if is_full_batch {
+            continue;
+        }
+
+        // wait until there is something new for this user
+        loop {
+            tokio::select! {
+                _ = changes.closed() => return,
+                notification = notifications.recv() => match notification {
+                    Ok(Notification::Changed(addr)) if addr == user_addr => break,
+                    Ok(Notification::Changed(_)) => continue,
+                    Ok(Notification::Erased(addr)) if addr == user_addr => {
+                        if changes.send(Event::Erased).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Notification::Erased(_)) => continue,
+                    Ok(Notification::Reset) => break,
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    }
+}
+
+async fn listen(db_url: String, feed: ChangeFeed) {
+    loop {
+        if let Err(e) = listen_once(&db_url, &feed).await {
+            error!("Change notifications listener failed: {}", e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once(db_url: &str, feed: &ChangeFeed) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(db_url, NoTls).await?;
+
+    // notifications only arrive while the connection is being polled
+    let connection = {
+        let feed = feed.clone();
+        tokio::spawn(async move {
+            while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+                if let AsyncMessage::Notification(notification) = message? {
+                    let user_addr = notification.payload().to_owned();
+                    match notification.channel() {
+                        ERASURES_CHANNEL => feed.erased(user_addr),
+                        _ => feed.notify(user_addr),
+                    }
+                }
+            }
+            Ok(())
+        })
+    };
+
+    client
+        .batch_execute(&format!("LISTEN {CHANNEL}; LISTEN {ERASURES_CHANNEL}"))
+        .await?;
+    info!(
+        "Listening for change notifications on {} and {}",
+        CHANNEL, ERASURES_CHANNEL
+    );
+    // whatever was notified of while not listening is lost
+    feed.reset();
+
+    connection
+        .await
+        .expect("change notifications listener panicked")
+}
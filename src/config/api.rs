@@ -1,4 +1,7 @@
+use jsonwebtoken::{jwk::JwkSet, DecodingKey};
 use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
 
 use crate::error::Error;
 
@@ -10,25 +13,253 @@ fn default_metrics_port() -> u16 {
     9090
 }
 
+fn default_auth_mode() -> AuthMode {
+    AuthMode::Header
+}
+
+fn default_chain_id() -> char {
+    'W'
+}
+
+fn default_signature_max_age_secs() -> u64 {
+    60
+}
+
+/// The signatures are remembered by each replica on its own for as long as their timestamps
+/// are acceptable, the window has to be short for that to be bearable.
+const SIGNATURE_MAX_AGE_LIMIT_SECS: u64 = 300;
+
+fn default_jwt_address_claim() -> String {
+    "address".to_string()
+}
+
+fn default_max_raw_body_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AuthMode {
+    Header,
+    Signature,
+    Jwt,
+}
+
 #[derive(Deserialize)]
 struct ConfigFlat {
     #[serde(default = "default_port")]
     port: u16,
     #[serde(default = "default_metrics_port")]
     metrics_port: u16,
+    #[serde(default = "default_auth_mode")]
+    auth_mode: AuthMode,
+    #[serde(default = "default_chain_id")]
+    chain_id: char,
+    #[serde(default = "default_signature_max_age_secs")]
+    signature_max_age_secs: u64,
+    jwt_jwks_path: Option<String>,
+    #[serde(default = "default_jwt_address_claim")]
+    jwt_address_claim: String,
+    quota_max_entries: Option<i64>,
+    quota_max_bytes: Option<i64>,
+    rate_limit_read_per_sec: Option<f64>,
+    rate_limit_read_burst: Option<u32>,
+    rate_limit_write_per_sec: Option<f64>,
+    rate_limit_write_burst: Option<u32>,
+    #[serde(default)]
+    rate_limit_by_ip: bool,
+    #[serde(default)]
+    rate_limit_trusted_proxies: usize,
+    #[serde(default = "default_max_raw_body_size")]
+    max_raw_body_size: u64,
+    admin_port: Option<u16>,
+    admin_token: Option<String>,
+}
+
+/// How the user a request is made on behalf of is determined.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// The `X-User-Address` header set by a trusted gateway
+    Header,
+    /// The address derived from the public key the request is signed with. A signature is
+    /// rejected as replayed by the replica which has seen it only, so the mode supports
+    /// a single replica.
+    Signature {
+        /// How far the request timestamp may be off the current time, at most 5 minutes
+        max_age: Duration,
+    },
+    /// The address claim of the `Authorization: Bearer` JWT
+    Jwt(JwtConfig),
+}
+
+#[derive(Clone)]
+pub struct JwtConfig {
+    /// Keys the tokens are verified with, along with their key ids
+    pub keys: Vec<(Option<String>, DecodingKey)>,
+    pub address_claim: String,
+}
+
+impl fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the keys may be secret
+        let key_ids = self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        f.debug_struct("JwtConfig")
+            .field("key_ids", &key_ids)
+            .field("address_claim", &self.address_claim)
+            .finish()
+    }
+}
+
+/// Per-user storage limits, `None` meaning unlimited.
+/// Expired entries count until they are removed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    pub max_entries: Option<i64>,
+    pub max_bytes: Option<i64>,
+}
+
+/// Token bucket refilled with `per_second` tokens a second up to `burst` tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Request rate limits per user and, optionally, per client IP, `None` meaning unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub read: Option<Budget>,
+    pub write: Option<Budget>,
+    pub by_ip: bool,
+    /// The number of proxies in front of the service appending to `X-Forwarded-For`,
+    /// 0 meaning the client IP is the address of the connection
+    pub trusted_proxies: usize,
+}
+
+/// The admin API, bound to its own port and authorized with a static bearer token.
+#[derive(Clone)]
+pub struct Admin {
+    pub port: u16,
+    pub token: String,
+}
+
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin").field("port", &self.port).finish()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
     pub metrics_port: u16,
+    /// Chain id byte of the user addresses, e.g. `W` for mainnet
+    pub chain_id: u8,
+    pub auth: Auth,
+    pub quota: Quota,
+    pub rate_limit: RateLimit,
+    /// Size limit in bytes of the bodies taken as is, those of the import and the patch
+    pub max_raw_body_size: u64,
+    /// `None` if the admin API is disabled
+    pub admin: Option<Admin>,
 }
 
 pub fn load() -> Result<Config, Error> {
     let api_config_flat = envy::from_env::<ConfigFlat>()?;
 
+    let auth = match api_config_flat.auth_mode {
+        AuthMode::Header => Auth::Header,
+        AuthMode::Signature => Auth::Signature {
+            max_age: signature_max_age(api_config_flat.signature_max_age_secs)?,
+        },
+        AuthMode::Jwt => {
+            let jwks_path = api_config_flat.jwt_jwks_path.ok_or_else(|| {
+                Error::GeneralError("JWT_JWKS_PATH is required in the jwt auth mode".to_string())
+            })?;
+            Auth::Jwt(JwtConfig {
+                keys: load_jwks(&jwks_path)?,
+                address_claim: api_config_flat.jwt_address_claim,
+            })
+        }
+    };
+
+    let admin = match (api_config_flat.admin_port, api_config_flat.admin_token) {
+        (Some(port), Some(token)) if !token.is_empty() => Some(Admin { port, token }),
+        (None, None) => None,
+        _ => {
+            return Err(Error::GeneralError(
+                "ADMIN_PORT and ADMIN_TOKEN are required together".to_string(),
+            ))
+        }
+    };
+
     Ok(Config {
         port: api_config_flat.port,
         metrics_port: api_config_flat.metrics_port,
+        chain_id: chain_id(api_config_flat.chain_id)?,
+        auth,
+        quota: Quota {
+            max_entries: api_config_flat.quota_max_entries,
+            max_bytes: api_config_flat.quota_max_bytes,
+        },
+        rate_limit: RateLimit {
+            read: budget(
+                api_config_flat.rate_limit_read_per_sec,
+                api_config_flat.rate_limit_read_burst,
+            )?,
+            write: budget(
+                api_config_flat.rate_limit_write_per_sec,
+                api_config_flat.rate_limit_write_burst,
+            )?,
+            by_ip: api_config_flat.rate_limit_by_ip,
+            trusted_proxies: api_config_flat.rate_limit_trusted_proxies,
+        },
+        max_raw_body_size: api_config_flat.max_raw_body_size,
+        admin,
     })
 }
+
+fn chain_id(chain_id: char) -> Result<u8, Error> {
+    u8::try_from(chain_id)
+        .ok()
+        .filter(u8::is_ascii)
+        .ok_or_else(|| Error::GeneralError(format!("invalid chain id: {chain_id}")))
+}
+
+fn signature_max_age(secs: u64) -> Result<Duration, Error> {
+    if secs == 0 || secs > SIGNATURE_MAX_AGE_LIMIT_SECS {
+        return Err(Error::GeneralError(format!(
+            "invalid signature max age: {secs} seconds, 1 to {SIGNATURE_MAX_AGE_LIMIT_SECS} are allowed"
+        )));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// The burst defaults to a second worth of tokens.
+fn budget(per_second: Option<f64>, burst: Option<u32>) -> Result<Option<Budget>, Error> {
+    let per_second = match per_second {
+        Some(per_second) if per_second > 0.0 => per_second,
+        Some(per_second) => {
+            return Err(Error::GeneralError(format!(
+                "invalid rate limit: {per_second} per second"
+            )))
+        }
+        None => return Ok(None),
+    };
+    let burst = burst.unwrap_or_else(|| per_second.ceil() as u32).max(1);
+    Ok(Some(Budget { per_second, burst }))
+}
+
+fn load_jwks(path: &str) -> Result<Vec<(Option<String>, DecodingKey)>, Error> {
+    let invalid = |e: &dyn fmt::Display| Error::GeneralError(format!("invalid JWKS {path}: {e}"));
+
+    let jwks = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+    let jwks = serde_json::from_str::<JwkSet>(&jwks).map_err(|e| invalid(&e))?;
+    jwks.keys
+        .iter()
+        .map(|jwk| {
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| invalid(&e))?;
+            Ok((jwk.common.key_id.clone(), key))
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::error::Error;
+
+fn default_ttl_secs() -> u64 {
+    60
+}
+
+fn default_stale_retry_secs() -> u64 {
+    5
+}
+
+#[derive(Deserialize)]
+struct ConfigFlat {
+    capacity: Option<usize>,
+    #[serde(default = "default_ttl_secs")]
+    ttl_secs: u64,
+    #[serde(default = "default_stale_retry_secs")]
+    stale_retry_secs: u64,
+}
+
+/// The read-through cache of the entries, kept by every service replica on its own.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How many keys are kept at most, the least recently used ones being evicted first
+    pub capacity: usize,
+    /// How long an entry is served from the cache at most, should its invalidation get lost
+    pub ttl: Duration,
+    /// How long the reads are served from the cache regardless of the TTL,
+    /// once the storage has turned out unavailable, before it is tried again
+    pub stale_retry: Duration,
+}
+
+/// Returns `None` if the cache is disabled, which it is unless `CACHE_CAPACITY` is set.
+pub fn load() -> Result<Option<Config>, Error> {
+    let config_flat = envy::prefixed("CACHE_").from_env::<ConfigFlat>()?;
+
+    Ok(match config_flat.capacity {
+        Some(capacity) if capacity > 0 => Some(Config {
+            capacity,
+            ttl: Duration::from_secs(config_flat.ttl_secs),
+            stale_retry: Duration::from_secs(config_flat.stale_retry_secs),
+        }),
+        _ => None,
+    })
+}
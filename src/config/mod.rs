@@ -1,5 +1,8 @@
 pub mod api;
+pub mod cache;
 pub mod postgres;
+pub mod reaper;
+pub mod storage;
 
 use crate::error::Error;
 use wavesexchange_repos::circuit_breaker;
@@ -7,14 +10,19 @@ use wavesexchange_repos::circuit_breaker;
 #[derive(Debug)]
 pub struct Config {
     pub api: api::Config,
-    pub pg: postgres::Config,
+    pub storage: storage::Config,
     pub cb: circuit_breaker::Config,
+    /// `None` if the cache is disabled
+    pub cache: Option<cache::Config>,
+    pub reaper: reaper::Config,
 }
 
 pub fn load() -> Result<Config, Error> {
     Ok(Config {
         api: api::load()?,
-        pg: postgres::load()?,
+        storage: storage::load()?,
         cb: circuit_breaker::config::load()?,
+        cache: cache::load()?,
+        reaper: reaper::load()?,
     })
 }
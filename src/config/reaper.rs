@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::error::Error;
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_batch_size() -> u32 {
+    1000
+}
+
+fn default_changes_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Deserialize)]
+struct ConfigFlat {
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    #[serde(default = "default_batch_size")]
+    batch_size: u32,
+    #[serde(default = "default_changes_retention_secs")]
+    changes_retention_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub interval: Duration,
+    pub batch_size: u32,
+    /// How long the change feed keeps changes for the subscribers to resume from
+    pub changes_retention: Duration,
+}
+
+pub fn load() -> Result<Config, Error> {
+    let config_flat = envy::prefixed("REAPER_").from_env::<ConfigFlat>()?;
+
+    Ok(Config {
+        interval: Duration::from_secs(config_flat.interval_secs),
+        batch_size: config_flat.batch_size,
+        changes_retention: Duration::from_secs(config_flat.changes_retention_secs),
+    })
+}
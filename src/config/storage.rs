@@ -0,0 +1,57 @@
+use serde::Deserialize;
+
+use crate::config::postgres;
+use crate::error::Error;
+
+fn default_backend() -> Backend {
+    Backend::Postgres
+}
+
+#[cfg(feature = "sqlite")]
+fn default_sqlite_path() -> String {
+    "user-storage.db".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    Postgres,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    Memory,
+}
+
+#[derive(Deserialize)]
+struct ConfigFlat {
+    #[serde(default = "default_backend")]
+    backend: Backend,
+    #[cfg(feature = "sqlite")]
+    #[serde(default = "default_sqlite_path")]
+    sqlite_path: String,
+}
+
+/// Where the entries are stored.
+#[derive(Debug, Clone)]
+pub enum Config {
+    Postgres(postgres::Config),
+    /// Database file, created if missing
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: String,
+    },
+    /// Nothing survives a restart, meant for tests and local development
+    Memory,
+}
+
+pub fn load() -> Result<Config, Error> {
+    let config_flat = envy::prefixed("STORAGE_").from_env::<ConfigFlat>()?;
+
+    Ok(match config_flat.backend {
+        Backend::Postgres => Config::Postgres(postgres::load()?),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Config::Sqlite {
+            path: config_flat.sqlite_path,
+        },
+        Backend::Memory => Config::Memory,
+    })
+}
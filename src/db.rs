@@ -31,8 +31,9 @@ pub fn async_pool(config: &Config) -> Result<PgAsyncPool, Error> {
 impl FallibleDataSource for PgAsyncPool {
     type Error = Error;
 
-    fn is_countable_err(_err: &Self::Error) -> bool {
-        //err.to_string().contains("no connection to the server")
-        true
+    /// Only the storage being unreachable opens the circuit, the requests failing on their own
+    /// (missing keys, invalid entries, failed preconditions) don't.
+    fn is_countable_err(err: &Self::Error) -> bool {
+        err.is_unavailable()
     }
 }
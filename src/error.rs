@@ -1,4 +1,6 @@
+use diesel::result::DatabaseErrorKind;
 use std::collections::HashMap;
+use std::time::Duration;
 use warp::reject::Reject;
 
 #[derive(Debug, thiserror::Error)]
@@ -12,6 +14,24 @@ pub enum Error {
     #[error("KeyNotFound: {0}")]
     KeyNotFound(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("TooManyRequests: retry after {0:?}")]
+    TooManyRequests(Duration),
+
+    #[error("PreconditionFailed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("PayloadTooLarge: more than {0} bytes")]
+    PayloadTooLarge(u64),
+
+    #[error("UnsupportedMediaType: {0}")]
+    UnsupportedMediaType(String),
+
     #[error("SerdeError: {0}")]
     SerdeError(#[from] serde_json::Error),
 
@@ -21,8 +41,31 @@ pub enum Error {
     #[error("PoolError")]
     PoolError(#[from] deadpool_diesel::PoolError),
 
+    #[error("StorageUnavailable: {0}")]
+    StorageUnavailable(String),
+
     #[error("GeneralError: {0}")]
     GeneralError(String),
 }
 
+impl Error {
+    /// Whether the error is due to the storage being unreachable rather than the request,
+    /// so that the same request may well succeed later.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Error::StorageUnavailable(_) => true,
+            // a timeout waiting for a connection is rather due to the load, the pool being busy
+            Error::PoolError(e) => matches!(
+                e,
+                deadpool_diesel::PoolError::Backend(_) | deadpool_diesel::PoolError::Closed
+            ),
+            Error::DbDieselError(diesel::result::Error::DatabaseError(kind, _)) => matches!(
+                kind,
+                DatabaseErrorKind::ClosedConnection | DatabaseErrorKind::UnableToSendCommand
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl Reject for Error {}
@@ -1,10 +1,13 @@
 pub mod api;
+pub mod changes;
 pub mod config;
 pub mod db;
 pub mod error;
 pub mod models;
+pub mod reaper;
 pub mod repo;
 pub mod schema;
+pub mod waves;
 
 #[macro_use]
 extern crate async_trait;
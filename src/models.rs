@@ -1,11 +1,12 @@
 use crate::schema::*;
-use diesel::{AsChangeset, Identifiable, Insertable, Queryable};
+use chrono::{DateTime, Utc};
+use diesel::{AsChangeset, Identifiable, Insertable, Queryable, QueryableByName};
 use serde_json::Value;
 
 pub type Key = String;
 pub type UserAddress = String;
 
-#[derive(Insertable, Queryable, Identifiable, AsChangeset)]
+#[derive(Clone, Insertable, Queryable, QueryableByName, Identifiable, AsChangeset)]
 #[diesel(table_name = user_storage)]
 #[diesel(primary_key(key, user_addr))]
 pub struct UserStorageEntry {
@@ -17,11 +18,72 @@ pub struct UserStorageEntry {
     pub entry_value_integer: Option<i64>,
     pub entry_value_json: Option<Value>,
     pub entry_value_string: Option<String>,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_storage_changes)]
+pub struct NewUserStorageChange {
+    pub user_addr: UserAddress,
+    pub key: Key,
+    pub entry: Option<Value>,
+    pub version: i64,
+}
+
+#[derive(Clone, Queryable)]
+pub struct UserStorageChange {
+    pub id: i64,
+    pub user_addr: UserAddress,
+    pub key: Key,
+    pub entry: Option<Value>,
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A revision of an entry, the entry type and values are `None` for a deletion.
+#[derive(Insertable)]
+#[diesel(table_name = user_storage_history)]
+pub struct NewUserStorageRevision {
+    pub user_addr: UserAddress,
+    pub key: Key,
+    pub entry_type: Option<String>,
+    pub entry_value_binary: Option<String>,
+    pub entry_value_boolean: Option<bool>,
+    pub entry_value_integer: Option<i64>,
+    pub entry_value_json: Option<Value>,
+    pub entry_value_string: Option<String>,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Queryable)]
+pub struct UserStorageRevision {
+    pub id: i64,
+    pub user_addr: UserAddress,
+    pub key: Key,
+    pub entry_type: Option<String>,
+    pub entry_value_binary: Option<String>,
+    pub entry_value_boolean: Option<bool>,
+    pub entry_value_integer: Option<i64>,
+    pub entry_value_json: Option<Value>,
+    pub entry_value_string: Option<String>,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Number and total size of the entries of a user, maintained by a trigger on `user_storage`.
+#[derive(Clone, Debug, Default, Queryable)]
+pub struct UserStorageUsage {
+    pub entry_count: i64,
+    pub total_bytes: i64,
 }
 
 pub mod dto {
     use super::*;
     use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
     pub struct KeyEntryList {
@@ -37,6 +99,28 @@ pub mod dto {
     pub struct KeyEntryPair {
         pub key: Key,
         pub entry: Option<Entry>,
+        /// Expected current version of the entry, the write is rejected if it differs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub version: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub ttl_seconds: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub expires_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct IncrRequest {
+        pub delta: i64,
+        /// Lower bound the resulting value is clamped to
+        pub min: Option<i64>,
+        /// Upper bound the resulting value is clamped to
+        pub max: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct ExpiryQuery {
+        pub ttl_seconds: Option<u32>,
+        pub expires_at: Option<DateTime<Utc>>,
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -50,10 +134,229 @@ pub mod dto {
         String(String),
     }
 
+    /// A change of a single entry, `entry` is `None` if the entry was deleted.
+    /// A deletion gets the version following the last version of the deleted entry.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Change {
+        pub key: Key,
+        pub entry: Option<Entry>,
+        pub version: i64,
+    }
+
+    /// Keys and key prefixes a WebSocket client wants (or no longer wants) the changes of.
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Subscription {
+        #[serde(default)]
+        pub keys: Vec<Key>,
+        #[serde(default)]
+        pub prefixes: Vec<String>,
+    }
+
+    /// A message sent by a WebSocket client.
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum WsRequest {
+        Subscribe(Subscription),
+        Unsubscribe(Subscription),
+        Get {
+            request_id: String,
+            keys: Vec<Key>,
+        },
+        Set {
+            request_id: String,
+            entries: Vec<KeyEntryPair>,
+        },
+    }
+
+    /// A message sent to a WebSocket client.
+    /// `Entries` answers both `get` (current entries) and `set` (old entries) requests,
+    /// `Erased` tells that all the entries are gone, with no `Change` sent for each of them.
+    #[derive(Clone, Debug, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum WsResponse {
+        Change {
+            id: i64,
+            #[serde(flatten)]
+            change: Change,
+        },
+        Erased,
+        Entries {
+            request_id: String,
+            entries: Vec<Option<Entry>>,
+        },
+        Error {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+            message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            details: Option<HashMap<String, String>>,
+        },
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct ChangesQuery {
+        #[serde(default)]
+        pub prefix: String,
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct Revision {
+        pub id: i64,
+        pub entry: Option<Entry>,
+        pub version: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub expires_at: Option<DateTime<Utc>>,
+        pub created_at: DateTime<Utc>,
+    }
+
+    /// Revisions of an entry, the most recent first.
+    #[derive(Clone, Debug, Serialize)]
+    pub struct RevisionList {
+        pub revisions: Vec<Revision>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_cursor: Option<String>,
+    }
+
+    /// Identifies the revision to restore, either by its id or as the one current at the given moment.
+    /// The restored entry keeps the expiry of the revision unless a new one is given,
+    /// which is required if the revision has expired already.
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct RestoreRequest {
+        pub revision_id: Option<i64>,
+        pub at: Option<DateTime<Utc>>,
+        pub ttl_seconds: Option<u32>,
+        pub expires_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct UserUsage {
+        pub user_addr: UserAddress,
+        pub entry_count: i64,
+        pub total_bytes: i64,
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct UserList {
+        pub users: Vec<UserUsage>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_cursor: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct UsersQuery {
+        pub limit: Option<u32>,
+        pub after: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct HistoryQuery {
+        pub limit: Option<u32>,
+        pub after: Option<String>,
+    }
+
     #[derive(Clone, Debug, Deserialize)]
     pub struct KeyList {
         pub keys: Vec<Key>,
     }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct KeyPathList {
+        pub keys: Vec<Key>,
+        /// JSON paths to narrow `Entry::Json` values of the given keys down to
+        #[serde(default)]
+        pub paths: HashMap<Key, String>,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct EntryQuery {
+        pub path: Option<String>,
+        /// Returns the entry as it was at the given moment
+        pub at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct KeyPrefixQuery {
+        #[serde(default)]
+        pub prefix: String,
+        #[serde(default)]
+        pub with_entries: bool,
+        pub limit: Option<u32>,
+        pub after: Option<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExportFormat {
+        /// A `KeyEntryList` document
+        #[default]
+        Json,
+        /// One `KeyEntryPair` per line
+        Ndjson,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct ExportQuery {
+        #[serde(default)]
+        pub format: ExportFormat,
+    }
+
+    /// How an import treats the existing entries of the user.
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ImportStrategy {
+        /// Overwrites the existing entries with the imported ones
+        #[default]
+        Overwrite,
+        /// Keeps the existing entries, importing the missing ones only
+        SkipExisting,
+        /// Overwrites the existing entries and deletes the ones not imported
+        ReplaceAll,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct ImportQuery {
+        #[serde(default)]
+        pub strategy: ImportStrategy,
+    }
+
+    /// Numbers of entries affected by an import.
+    /// Skipped ones are either existing with `skip_existing` or already expired.
+    #[derive(Clone, Debug, Default, Serialize)]
+    pub struct ImportSummary {
+        pub created: usize,
+        pub updated: usize,
+        pub skipped: usize,
+        pub deleted: usize,
+    }
+
+    /// `?all=true&confirm=<user address>`, deletes all the data of the user.
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct EraseQuery {
+        #[serde(default)]
+        pub all: bool,
+        pub confirm: Option<UserAddress>,
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct ErasureResult {
+        pub deleted: usize,
+    }
+
+    /// Paging of the batch reads, which are paged once either is given.
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct PageQuery {
+        pub limit: Option<u32>,
+        pub after: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct KeyListing {
+        pub keys: Vec<Key>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub entries: Option<Vec<Entry>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_cursor: Option<String>,
+    }
 }
 
 impl From<UserStorageEntry> for dto::Entry {
@@ -69,6 +372,19 @@ impl From<UserStorageEntry> for dto::Entry {
     }
 }
 
+impl From<UserStorageEntry> for dto::KeyEntryPair {
+    fn from(entry: UserStorageEntry) -> Self {
+        dto::KeyEntryPair {
+            key: entry.key.clone(),
+            version: None,
+            ttl_seconds: None,
+            expires_at: entry.expires_at,
+            entry: Some(entry.into()),
+        }
+    }
+}
+
+/// The version of the entry is assigned by the repo once it is written.
 impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
     fn from((user_addr, key, entry): (UserAddress, Key, dto::Entry)) -> Self {
         match entry {
@@ -81,6 +397,8 @@ impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
                 entry_value_integer: None,
                 entry_value_json: None,
                 entry_value_string: None,
+                version: 1,
+                expires_at: None,
             },
             dto::Entry::Boolean(val) => UserStorageEntry {
                 key,
@@ -91,6 +409,8 @@ impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
                 entry_value_integer: None,
                 entry_value_json: None,
                 entry_value_string: None,
+                version: 1,
+                expires_at: None,
             },
             dto::Entry::Integer(val) => UserStorageEntry {
                 key,
@@ -101,6 +421,8 @@ impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
                 entry_value_integer: Some(val),
                 entry_value_json: None,
                 entry_value_string: None,
+                version: 1,
+                expires_at: None,
             },
             dto::Entry::Json(val) => UserStorageEntry {
                 key,
@@ -111,6 +433,8 @@ impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
                 entry_value_integer: None,
                 entry_value_json: Some(val),
                 entry_value_string: None,
+                version: 1,
+                expires_at: None,
             },
             dto::Entry::String(val) => UserStorageEntry {
                 key,
@@ -121,7 +445,125 @@ impl From<(UserAddress, Key, dto::Entry)> for UserStorageEntry {
                 entry_value_integer: None,
                 entry_value_json: None,
                 entry_value_string: Some(val),
+                version: 1,
+                expires_at: None,
             },
         }
     }
 }
+
+impl From<(UserAddress, dto::Change)> for NewUserStorageChange {
+    fn from((user_addr, change): (UserAddress, dto::Change)) -> Self {
+        NewUserStorageChange {
+            user_addr,
+            key: change.key,
+            entry: change.entry.map(|e| serde_json::to_value(e).unwrap()),
+            version: change.version,
+        }
+    }
+}
+
+impl TryFrom<UserStorageChange> for dto::Change {
+    type Error = serde_json::Error;
+
+    fn try_from(change: UserStorageChange) -> Result<Self, Self::Error> {
+        Ok(dto::Change {
+            key: change.key,
+            entry: change.entry.map(serde_json::from_value).transpose()?,
+            version: change.version,
+        })
+    }
+}
+
+impl dto::Change {
+    pub fn updated(key: Key, entry: dto::Entry, version: i64) -> Self {
+        dto::Change {
+            key,
+            entry: Some(entry),
+            version,
+        }
+    }
+
+    pub fn deleted(key: Key, last_version: i64) -> Self {
+        dto::Change {
+            key,
+            entry: None,
+            version: last_version + 1,
+        }
+    }
+}
+
+impl From<&UserStorageEntry> for NewUserStorageRevision {
+    fn from(entry: &UserStorageEntry) -> Self {
+        NewUserStorageRevision {
+            user_addr: entry.user_addr.clone(),
+            key: entry.key.clone(),
+            entry_type: Some(entry.entry_type.clone()),
+            entry_value_binary: entry.entry_value_binary.clone(),
+            entry_value_boolean: entry.entry_value_boolean,
+            entry_value_integer: entry.entry_value_integer,
+            entry_value_json: entry.entry_value_json.clone(),
+            entry_value_string: entry.entry_value_string.clone(),
+            version: entry.version,
+            expires_at: entry.expires_at,
+        }
+    }
+}
+
+impl NewUserStorageRevision {
+    /// A deletion gets the version following the last version of the deleted entry.
+    pub fn deleted(user_addr: UserAddress, key: Key, last_version: i64) -> Self {
+        NewUserStorageRevision {
+            user_addr,
+            key,
+            entry_type: None,
+            entry_value_binary: None,
+            entry_value_boolean: None,
+            entry_value_integer: None,
+            entry_value_json: None,
+            entry_value_string: None,
+            version: last_version + 1,
+            expires_at: None,
+        }
+    }
+}
+
+impl UserStorageRevision {
+    /// The entry as it was after this revision, `None` if it was deleted.
+    pub fn entry(self) -> Option<UserStorageEntry> {
+        Some(UserStorageEntry {
+            key: self.key,
+            user_addr: self.user_addr,
+            entry_type: self.entry_type?,
+            entry_value_binary: self.entry_value_binary,
+            entry_value_boolean: self.entry_value_boolean,
+            entry_value_integer: self.entry_value_integer,
+            entry_value_json: self.entry_value_json,
+            entry_value_string: self.entry_value_string,
+            version: self.version,
+            expires_at: self.expires_at,
+        })
+    }
+}
+
+impl From<UserStorageRevision> for dto::Revision {
+    fn from(revision: UserStorageRevision) -> Self {
+        dto::Revision {
+            id: revision.id,
+            version: revision.version,
+            expires_at: revision.expires_at,
+            created_at: revision.created_at,
+            entry: revision.entry().map(dto::Entry::from),
+        }
+    }
+}
+
+impl From<(UserAddress, UserStorageUsage)> for dto::UserUsage {
+    fn from((user_addr, usage): (UserAddress, UserStorageUsage)) -> Self {
+        dto::UserUsage {
+            user_addr,
+            entry_count: usage.entry_count,
+            total_bytes: usage.total_bytes,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::reaper::Config;
+use crate::error::Error;
+use crate::repo::{Repo, RepoOperations};
+use chrono::Utc;
+use std::sync::Arc;
+use wavesexchange_log::{debug, error};
+
+/// Periodically removes expired entries and changes older than the retention period.
+///
+/// Rows are deleted in batches of at most `batch_size`, so that a large backlog
+/// never turns into a single long-running delete.
+pub async fn run<R: Repo>(repo: Arc<R>, config: Config) {
+    let batch_size = config.batch_size as i64;
+    let changes_retention = chrono::Duration::from_std(config.changes_retention)
+        .expect("changes retention period is out of range");
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        delete_in_batches(&*repo, "expired entries", batch_size, move |ops| {
+            ops.delete_expired(batch_size)
+        })
+        .await;
+
+        let before = Utc::now() - changes_retention;
+        delete_in_batches(&*repo, "outdated changes", batch_size, move |ops| {
+            ops.delete_changes_before(before, batch_size)
+        })
+        .await;
+    }
+}
+
+async fn delete_in_batches<R, F>(repo: &R, what: &str, batch_size: i64, delete_batch: F)
+where
+    R: Repo,
+    F: for<'a> Fn(&mut R::Operations<'a>) -> Result<usize, Error>,
+    F: Clone + Send + Sync + 'static,
+{
+    loop {
+        match repo.interact(delete_batch.clone()).await {
+            Ok(deleted) => {
+                if deleted > 0 {
+                    debug!("Removed {} {}", deleted, what);
+                }
+                if (deleted as i64) < batch_size {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to remove {}: {}", what, e);
+                break;
+            }
+        }
+    }
+}
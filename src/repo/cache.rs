@@ -0,0 +1,648 @@
+//! Repo decorator serving the reads of entries from an in-process LRU cache.
+//!
+//! The cached entries of a user are dropped by the writes made through the decorator,
+//! and by the change feed notifications, which cover the writes of the other service replicas
+//! as well. So an entry may be served stale for as long as the notification takes to arrive,
+//! and for the TTL at most should it never do. The whole cache is dropped when the feed
+//! may have missed notifications, as it does while reconnecting to Postgres.
+//!
+//! Reads in transactions are never served from the cache, as they back the conditional writes.
+//!
+//! Once the wrapped repo turns out unavailable, the reads are served from the cache alone,
+//! regardless of the TTL, while the writes and the reads of what is not cached are rejected.
+//! The repo is tried again every so often, and the first success ends this degraded mode.
+
+use super::{Key, Repo, RepoOperations};
+use crate::changes::{ChangeFeed, Notification};
+use crate::config::cache::Config;
+use crate::error::Error;
+use crate::models::{
+    dto::Change, UserAddress, UserStorageChange, UserStorageEntry, UserStorageRevision,
+    UserStorageUsage,
+};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use wavesexchange_log::{info, warn};
+
+lazy_static! {
+    pub static ref CACHE_HITS: IntCounter = IntCounter::new(
+        "user_storage_cache_hits",
+        "Number of the keys read from the cache"
+    )
+    .unwrap();
+    pub static ref CACHE_MISSES: IntCounter = IntCounter::new(
+        "user_storage_cache_misses",
+        "Number of the keys read through the cache from the storage"
+    )
+    .unwrap();
+}
+
+/// Users are spread over this many invalidation counters,
+/// telling whether a read from the storage raced a write.
+const EPOCH_STRIPES: usize = 256;
+
+pub struct CachedRepo<R> {
+    inner: R,
+    cache: Arc<Cache>,
+    stale_retry: Duration,
+    /// When the wrapped repo has last turned out unavailable, `None` if it has recovered since
+    unavailable_at: Mutex<Option<Instant>>,
+}
+
+/// Wraps the repo, dropping the cached entries of the users the feed notifies of.
+pub fn new<R: Repo>(inner: R, config: &Config, changes: &ChangeFeed) -> CachedRepo<R> {
+    let cache = Arc::new(Cache::new(config.capacity, config.ttl));
+    tokio::spawn(invalidate_on_changes(cache.clone(), changes.subscribe()));
+    CachedRepo {
+        inner,
+        cache,
+        stale_retry: config.stale_retry,
+        unavailable_at: Mutex::new(None),
+    }
+}
+
+async fn invalidate_on_changes(
+    cache: Arc<Cache>,
+    mut notifications: broadcast::Receiver<Notification>,
+) {
+    loop {
+        match notifications.recv().await {
+            Ok(Notification::Changed(user_addr)) | Ok(Notification::Erased(user_addr)) => {
+                cache.invalidate(&user_addr)
+            }
+            // there is no telling whose entries have changed meanwhile
+            Ok(Notification::Reset) | Err(broadcast::error::RecvError::Lagged(_)) => cache.clear(),
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[async_trait]
+impl<R, O> Repo for CachedRepo<R>
+where
+    R: for<'a> Repo<Operations<'a> = O>,
+    O: RepoOperations + 'static,
+{
+    type Operations<'a> = CachedOperations<'a, O>;
+
+    async fn interact<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<T, Error>,
+        F: Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        let (result, _) = self.interact_tracking_stale(f).await?;
+        Ok(result)
+    }
+
+    async fn interact_tracking_stale<F, T>(&self, f: F) -> Result<(T, bool), Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<T, Error>,
+        F: Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        if self.is_serving_stale() {
+            return self.interact_stale(f);
+        }
+
+        // kept here to be run on the cache alone,
+        // should the wrapped repo turn out unavailable before running it
+        let f = Arc::new(Mutex::new(Some(f)));
+        let result = {
+            let (f, cache) = (f.clone(), self.cache.clone());
+            self.inner
+                .interact(move |ops| {
+                    let f = take(&f).expect("interaction is run twice");
+                    let mut ops = CachedOperations::new(ops, cache.clone(), true);
+                    let result = f(&mut ops);
+                    // each write is committed on its own, even if a later one has failed
+                    for user_addr in ops.written.iter() {
+                        cache.invalidate(user_addr);
+                    }
+                    result
+                })
+                .await
+        };
+
+        match result {
+            Err(e) if e.is_unavailable() => {
+                self.set_unavailable(&e);
+                match take(&f) {
+                    Some(f) => self.interact_stale(f),
+                    None => Err(e),
+                }
+            }
+            result => {
+                self.set_available();
+                result.map(|result| (result, false))
+            }
+        }
+    }
+
+    async fn transaction<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<T, Error>,
+        F: Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        if self.is_serving_stale() {
+            return Err(unavailable());
+        }
+
+        let cache = self.cache.clone();
+        let result = self
+            .inner
+            .transaction(move |ops| {
+                let mut ops = CachedOperations::new(ops, cache, false);
+                let result = f(&mut ops)?;
+                Ok((result, ops.written))
+            })
+            .await;
+        match &result {
+            Err(e) if e.is_unavailable() => self.set_unavailable(e),
+            _ => self.set_available(),
+        }
+        let (result, written) = result?;
+
+        // the entries read before the commit have been stale since
+        for user_addr in written.iter() {
+            self.cache.invalidate(user_addr);
+        }
+        Ok(result)
+    }
+}
+
+impl<R> CachedRepo<R> {
+    fn unavailable_at(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.unavailable_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs the interaction on the cache alone, telling whether anything was read from it.
+    fn interact_stale<'a, O, F, T>(&self, f: F) -> Result<(T, bool), Error>
+    where
+        O: RepoOperations + 'a,
+        F: FnOnce(&mut CachedOperations<'a, O>) -> Result<T, Error>,
+    {
+        let mut ops = CachedOperations::stale(self.cache.clone());
+        let result = f(&mut ops)?;
+        Ok((result, ops.served_stale))
+    }
+
+    /// Whether the wrapped repo is not to be tried again yet.
+    fn is_serving_stale(&self) -> bool {
+        match *self.unavailable_at() {
+            Some(unavailable_at) => unavailable_at.elapsed() < self.stale_retry,
+            None => false,
+        }
+    }
+
+    fn set_unavailable(&self, e: &Error) {
+        if self.unavailable_at().replace(Instant::now()).is_none() {
+            warn!("Storage is unavailable, serving the cached entries: {}", e);
+        }
+    }
+
+    fn set_available(&self) {
+        if self.unavailable_at().take().is_some() {
+            info!("Storage is available again");
+        }
+    }
+}
+
+fn take<F>(f: &Mutex<Option<F>>) -> Option<F> {
+    f.lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
+
+fn unavailable() -> Error {
+    Error::StorageUnavailable("only the cached entries can be read".to_string())
+}
+
+/// Operations of the wrapped repo, lent to the decorator for the duration of an interaction.
+pub struct CachedOperations<'a, O> {
+    /// `None` if the wrapped repo is unavailable and only the cache is read
+    ops: Option<&'a mut O>,
+    cache: Arc<Cache>,
+    /// Whether the reads may be served from the cache
+    use_cache: bool,
+    /// Users whose entries have been written in this interaction
+    written: HashSet<UserAddress>,
+    /// Whether any of the entries read have been served from the cache alone
+    served_stale: bool,
+}
+
+impl<'a, O: RepoOperations> CachedOperations<'a, O> {
+    fn new(ops: &'a mut O, cache: Arc<Cache>, use_cache: bool) -> Self {
+        CachedOperations {
+            ops: Some(ops),
+            cache,
+            use_cache,
+            written: HashSet::new(),
+            served_stale: false,
+        }
+    }
+
+    fn stale(cache: Arc<Cache>) -> Self {
+        CachedOperations {
+            ops: None,
+            cache,
+            use_cache: true,
+            written: HashSet::new(),
+            served_stale: false,
+        }
+    }
+
+    fn ops(&mut self) -> Result<&mut O, Error> {
+        self.ops.as_deref_mut().ok_or_else(unavailable)
+    }
+
+    /// Whether the reads of the user's entries may be served from the cache,
+    /// which they may not after a write, as it is not committed yet in a transaction.
+    fn is_cached(&self, user_addr: &UserAddress) -> bool {
+        self.use_cache && !self.written.contains(user_addr)
+    }
+
+    fn write(&mut self, user_addr: &UserAddress) -> Result<&mut O, Error> {
+        self.written.insert(user_addr.clone());
+        self.ops()
+    }
+}
+
+impl<O: RepoOperations> RepoOperations for CachedOperations<'_, O> {
+    fn get(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        if !self.is_cached(user_addr) {
+            return self.ops()?.get(user_addr, key);
+        }
+
+        let key = key.to_string();
+        if let Some(entry) = self.cache.get(user_addr, &key, self.ops.is_none()) {
+            CACHE_HITS.inc();
+            self.served_stale |= self.ops.is_none();
+            return Ok(entry);
+        }
+        CACHE_MISSES.inc();
+
+        let epoch = self.cache.epoch(user_addr);
+        let entry = self.ops()?.get(user_addr, &key)?;
+        self.cache
+            .fill(user_addr, vec![(key, entry.clone())], epoch);
+        Ok(entry)
+    }
+
+    fn mget(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        if !self.is_cached(user_addr) {
+            return self.ops()?.mget(user_addr, keys);
+        }
+
+        let keys = keys.iter().map(|k| k.to_string()).collect::<BTreeSet<_>>();
+        let mut entries = vec![];
+        let mut missed_keys = vec![];
+        for key in keys {
+            match self.cache.get(user_addr, &key, self.ops.is_none()) {
+                Some(entry) => {
+                    CACHE_HITS.inc();
+                    self.served_stale |= self.ops.is_none();
+                    entries.extend(entry);
+                }
+                None => missed_keys.push(key),
+            }
+        }
+        CACHE_MISSES.inc_by(missed_keys.len() as u64);
+        if missed_keys.is_empty() {
+            return Ok(entries);
+        }
+
+        let epoch = self.cache.epoch(user_addr);
+        let found = self.ops()?.mget(user_addr, &missed_keys)?;
+        // the keys not found are cached as such too
+        let mut fetched = missed_keys
+            .into_iter()
+            .map(|key| (key, None))
+            .collect::<HashMap<_, _>>();
+        for entry in found.iter() {
+            fetched.insert(entry.key.clone(), Some(entry.clone()));
+        }
+        self.cache
+            .fill(user_addr, fetched.into_iter().collect(), epoch);
+
+        entries.extend(found);
+        Ok(entries)
+    }
+
+    fn get_path(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        path: &[String],
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        self.ops()?.get_path(user_addr, key, path)
+    }
+
+    fn mget_page(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        self.ops()?.mget_page(user_addr, keys, after, limit)
+    }
+
+    fn scan_prefix(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        self.ops()?.scan_prefix(user_addr, prefix, after, limit)
+    }
+
+    fn mget_for_update(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        self.ops()?.mget_for_update(user_addr, keys)
+    }
+
+    fn set(&mut self, entry: &UserStorageEntry) -> Result<i64, Error> {
+        self.write(&entry.user_addr)?.set(entry)
+    }
+
+    fn insert(&mut self, entry: &UserStorageEntry) -> Result<Option<i64>, Error> {
+        self.write(&entry.user_addr)?.insert(entry)
+    }
+
+    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<Vec<(String, i64)>, Error> {
+        for entry in entries {
+            self.write(&entry.user_addr)?;
+        }
+        self.ops()?.mset(entries)
+    }
+
+    fn incr(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        self.write(user_addr)?.incr(user_addr, key, delta, min, max)
+    }
+
+    fn mdel(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<(String, i64)>, Error> {
+        self.write(user_addr)?.mdel(user_addr, keys)
+    }
+
+    fn delete_all(&mut self, user_addr: &UserAddress) -> Result<usize, Error> {
+        self.write(user_addr)?.delete_all(user_addr)
+    }
+
+    fn history(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageRevision>, Error> {
+        self.ops()?.history(user_addr, key, after_id, limit)
+    }
+
+    fn get_revision(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        id: i64,
+    ) -> Result<Option<UserStorageRevision>, Error> {
+        self.ops()?.get_revision(user_addr, key, id)
+    }
+
+    fn get_at(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        at: DateTime<Utc>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        self.ops()?.get_at(user_addr, key, at)
+    }
+
+    fn usage(&mut self, user_addr: &UserAddress) -> Result<UserStorageUsage, Error> {
+        self.ops()?.usage(user_addr)
+    }
+
+    fn list_users(
+        &mut self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<(UserAddress, UserStorageUsage)>, Error> {
+        self.ops()?.list_users(after, limit)
+    }
+
+    fn delete_expired(&mut self, limit: i64) -> Result<usize, Error> {
+        // the cache doesn't serve the expired entries anyway
+        self.ops()?.delete_expired(limit)
+    }
+
+    fn publish(&mut self, user_addr: &UserAddress, changes: &[Change]) -> Result<(), Error> {
+        self.ops()?.publish(user_addr, changes)
+    }
+
+    fn changes_since(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<UserStorageChange>, Error> {
+        self.ops()?
+            .changes_since(user_addr, prefix, after_id, limit)
+    }
+
+    fn last_change_id(&mut self, user_addr: &UserAddress) -> Result<i64, Error> {
+        self.ops()?.last_change_id(user_addr)
+    }
+
+    fn delete_changes_before(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        self.ops()?.delete_changes_before(before, limit)
+    }
+}
+
+struct Cache {
+    state: Mutex<CacheState>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+struct CacheState {
+    users: HashMap<UserAddress, HashMap<String, CachedEntry>>,
+    /// Cached keys by their last use, the least recent first
+    recency: BTreeMap<u64, (UserAddress, String)>,
+    last_use: u64,
+    /// Invalidations made so far, by user stripe
+    epochs: Vec<u64>,
+}
+
+struct CachedEntry {
+    /// `None` if there is no such entry
+    entry: Option<UserStorageEntry>,
+    cached_at: Instant,
+    last_use: u64,
+}
+
+impl Cache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Cache {
+            state: Mutex::new(CacheState {
+                users: HashMap::new(),
+                recency: BTreeMap::new(),
+                last_use: 0,
+                epochs: vec![0; EPOCH_STRIPES],
+            }),
+            capacity,
+            ttl,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        // the state is consistent between the statements that may panic
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns `None` if the key is not cached, and `Some(None)` if it is cached as absent.
+    /// The entries cached for longer than the TTL are only returned if `stale` ones are allowed.
+    fn get(
+        &self,
+        user_addr: &UserAddress,
+        key: &str,
+        stale: bool,
+    ) -> Option<Option<UserStorageEntry>> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let cached = state.users.get_mut(user_addr)?.get_mut(key)?;
+        // kept until refilled, to be served should the storage turn out unavailable meanwhile
+        if !stale && cached.cached_at.elapsed() > self.ttl {
+            return None;
+        }
+
+        state.recency.remove(&cached.last_use);
+        state.last_use += 1;
+        cached.last_use = state.last_use;
+        state
+            .recency
+            .insert(state.last_use, (user_addr.clone(), key.to_string()));
+
+        Some(cached.entry.clone().filter(|entry| match entry.expires_at {
+            Some(expires_at) => expires_at > Utc::now(),
+            None => true,
+        }))
+    }
+
+    /// The epoch to `fill` the cache with what is about to be read from the storage.
+    fn epoch(&self, user_addr: &UserAddress) -> u64 {
+        self.lock().epochs[stripe(user_addr)]
+    }
+
+    /// Caches the entries read from the storage, unless the user's entries
+    /// may have been invalidated since the read started.
+    fn fill(
+        &self,
+        user_addr: &UserAddress,
+        entries: Vec<(String, Option<UserStorageEntry>)>,
+        epoch: u64,
+    ) {
+        let mut state = self.lock();
+        if state.epochs[stripe(user_addr)] != epoch {
+            return;
+        }
+
+        let cached_at = Instant::now();
+        for (key, entry) in entries {
+            state.remove(user_addr, &key);
+            state.last_use += 1;
+            let last_use = state.last_use;
+            state
+                .recency
+                .insert(last_use, (user_addr.clone(), key.clone()));
+            state.users.entry(user_addr.clone()).or_default().insert(
+                key,
+                CachedEntry {
+                    entry,
+                    cached_at,
+                    last_use,
+                },
+            );
+        }
+
+        while state.recency.len() > self.capacity {
+            let (user_addr, key) = match state.recency.values().next() {
+                Some(least_recent) => least_recent.clone(),
+                None => break,
+            };
+            state.remove(&user_addr, &key);
+        }
+    }
+
+    fn invalidate(&self, user_addr: &UserAddress) {
+        let mut state = self.lock();
+        state.epochs[stripe(user_addr)] += 1;
+        if let Some(entries) = state.users.remove(user_addr) {
+            for cached in entries.values() {
+                state.recency.remove(&cached.last_use);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.lock();
+        for epoch in state.epochs.iter_mut() {
+            *epoch += 1;
+        }
+        state.users.clear();
+        state.recency.clear();
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, user_addr: &UserAddress, key: &str) {
+        if let Some(entries) = self.users.get_mut(user_addr) {
+            if let Some(cached) = entries.remove(key) {
+                self.recency.remove(&cached.last_use);
+            }
+            if entries.is_empty() {
+                self.users.remove(user_addr);
+            }
+        }
+    }
+}
+
+fn stripe(user_addr: &UserAddress) -> usize {
+    let mut hasher = DefaultHasher::new();
+    user_addr.hash(&mut hasher);
+    (hasher.finish() % EPOCH_STRIPES as u64) as usize
+}
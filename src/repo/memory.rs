@@ -0,0 +1,708 @@
+//! Repo keeping everything in memory, for tests and local development.
+//!
+//! Operations are serialized by a single lock, and a transaction writes to the storage
+//! directly, logging how to undo each of its writes for them to be undone if it fails.
+
+use super::{clamped_sum, json_at, Key, Repo, RepoOperations};
+use crate::changes::{ChangeFeed, Notification};
+use crate::error::Error;
+use crate::models::{
+    dto::{Change, Entry},
+    NewUserStorageChange, NewUserStorageRevision, UserAddress, UserStorageChange, UserStorageEntry,
+    UserStorageRevision, UserStorageUsage,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard};
+
+pub struct MemRepo {
+    storage: Mutex<MemStorage>,
+    changes: ChangeFeed,
+}
+
+#[derive(Default)]
+pub struct MemStorage {
+    entries: BTreeMap<(UserAddress, String), UserStorageEntry>,
+    /// Revisions by their ids, which are increasing
+    history: BTreeMap<i64, UserStorageRevision>,
+    /// Changes by their ids, which are increasing
+    changes: BTreeMap<i64, UserStorageChange>,
+    last_revision_id: i64,
+    last_change_id: i64,
+    /// The highest versions the erased users reached, which new entries start above
+    version_floors: HashMap<UserAddress, i64>,
+    /// Users with changes published or erased since the last commit
+    notifications: Vec<Notification>,
+    /// The undo log of the transaction in progress, if any
+    transaction: Option<Transaction>,
+}
+
+struct Transaction {
+    /// The previous values of what the transaction wrote, the earliest write first
+    undo: Vec<Undo>,
+    last_revision_id: i64,
+    last_change_id: i64,
+}
+
+enum Undo {
+    Entry((UserAddress, String), Option<UserStorageEntry>),
+    Revision(i64, Option<UserStorageRevision>),
+    Change(i64, Option<UserStorageChange>),
+    VersionFloor(UserAddress, Option<i64>),
+}
+
+#[async_trait]
+impl Repo for MemRepo {
+    type Operations<'a> = MemStorage;
+
+    async fn interact<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        // like the statements outside of a transaction, the writes made before an error stay
+        let mut storage = self.lock();
+        let result = f(&mut storage);
+        self.notify(&mut storage);
+        result
+    }
+
+    async fn transaction<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        let mut storage = self.lock();
+        storage.begin();
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| f(&mut storage))) {
+            Ok(result) => result,
+            Err(panic) => {
+                storage.rollback();
+                panic::resume_unwind(panic);
+            }
+        };
+        if result.is_ok() {
+            storage.commit();
+            self.notify(&mut storage);
+        } else {
+            storage.rollback();
+        }
+        result
+    }
+}
+
+impl MemRepo {
+    fn lock(&self) -> MutexGuard<'_, MemStorage> {
+        // a panicked transaction is rolled back before the panic is propagated
+        self.storage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn notify(&self, storage: &mut MemStorage) {
+        for notification in storage.notifications.drain(..) {
+            match notification {
+                Notification::Changed(user_addr) => self.changes.notify(user_addr),
+                Notification::Erased(user_addr) => self.changes.erased(user_addr),
+                Notification::Reset => self.changes.reset(),
+            }
+        }
+    }
+}
+
+/// Creates an empty repo notifying the given feed of the published changes.
+pub fn new(changes: ChangeFeed) -> MemRepo {
+    MemRepo {
+        storage: Mutex::new(MemStorage::default()),
+        changes,
+    }
+}
+
+impl RepoOperations for MemStorage {
+    fn get(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        Ok(self.live_entry(user_addr, &key.to_string()).cloned())
+    }
+
+    fn mget(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let mut keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        Ok(keys
+            .iter()
+            .filter_map(|key| self.live_entry(user_addr, key).cloned())
+            .collect())
+    }
+
+    fn get_path(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        path: &[String],
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        Ok(self.get(user_addr, key)?.map(|entry| UserStorageEntry {
+            entry_value_json: entry
+                .entry_value_json
+                .as_ref()
+                .and_then(|value| json_at(value, path))
+                .cloned(),
+            ..entry
+        }))
+    }
+
+    fn mget_page(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let mut entries = self.mget(user_addr, keys)?;
+        entries.retain(|e| is_after(&e.key, after));
+        entries.truncate(limit as usize);
+        Ok(entries)
+    }
+
+    fn scan_prefix(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let now = Utc::now();
+        Ok(self
+            .user_entries(user_addr)
+            .filter(|e| e.key.starts_with(prefix))
+            .filter(|e| is_after(&e.key, after))
+            .filter(|e| !is_expired(e, now))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn mget_for_update(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        // the storage lock is held for the whole transaction anyway
+        self.mget(user_addr, keys)
+    }
+
+    fn set(&mut self, entry: &UserStorageEntry) -> Result<i64, Error> {
+        Ok(self.upsert(entry))
+    }
+
+    fn insert(&mut self, entry: &UserStorageEntry) -> Result<Option<i64>, Error> {
+        self.delete_if_expired(&entry.user_addr, &entry.key);
+
+        let id = (entry.user_addr.clone(), entry.key.clone());
+        if self.entries.contains_key(&id) {
+            return Ok(None);
+        }
+        let entry = UserStorageEntry {
+            version: self.next_version(&entry.user_addr, &entry.key),
+            ..entry.clone()
+        };
+        self.record_history(NewUserStorageRevision::from(&entry));
+        let version = entry.version;
+        self.put_entry(id, Some(entry));
+        Ok(Some(version))
+    }
+
+    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<Vec<(String, i64)>, Error> {
+        Ok(entries
+            .iter()
+            .map(|entry| (entry.key.clone(), self.upsert(entry)))
+            .collect())
+    }
+
+    fn incr(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        self.delete_if_expired(user_addr, &key);
+
+        let id = (user_addr.clone(), key);
+        let entry = match self.entries.get(&id) {
+            Some(old) if old.entry_type != "integer" => return Ok(None),
+            Some(old) => {
+                let value = old.entry_value_integer.unwrap_or_default();
+                UserStorageEntry {
+                    entry_value_integer: Some(clamped_sum(value, delta, min, max)?),
+                    version: old.version + 1,
+                    ..old.clone()
+                }
+            }
+            None => UserStorageEntry {
+                version: self.next_version(user_addr, &id.1),
+                ..UserStorageEntry::from((
+                    id.0.clone(),
+                    id.1.clone(),
+                    Entry::Integer(clamped_sum(0, delta, min, max)?),
+                ))
+            },
+        };
+
+        self.record_history(NewUserStorageRevision::from(&entry));
+        self.put_entry(id, Some(entry.clone()));
+        Ok(Some(entry))
+    }
+
+    fn mdel(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<(String, i64)>, Error> {
+        let mut deleted = vec![];
+        for key in keys {
+            if let Some(entry) = self.put_entry((user_addr.clone(), key.to_string()), None) {
+                self.record_history(NewUserStorageRevision::deleted(
+                    user_addr.clone(),
+                    entry.key.clone(),
+                    entry.version,
+                ));
+                deleted.push((entry.key, entry.version));
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn delete_all(&mut self, user_addr: &UserAddress) -> Result<usize, Error> {
+        let ids = self
+            .user_entries(user_addr)
+            .map(|e| (e.user_addr.clone(), e.key.clone()))
+            .collect::<Vec<_>>();
+        let revision_ids = self
+            .history
+            .values()
+            .filter(|r| &r.user_addr == user_addr)
+            .map(|r| r.id)
+            .collect::<Vec<_>>();
+        let change_ids = self
+            .changes
+            .values()
+            .filter(|c| &c.user_addr == user_addr)
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+
+        let mut last_version = self.version_floor(user_addr);
+        for id in ids.iter() {
+            if let Some(entry) = self.put_entry(id.clone(), None) {
+                last_version = last_version.max(entry.version);
+            }
+        }
+        for id in revision_ids {
+            if let Some(revision) = self.put_revision(id, None) {
+                last_version = last_version.max(revision.version);
+            }
+        }
+        for id in change_ids {
+            self.put_change(id, None);
+        }
+        self.put_version_floor(user_addr.clone(), Some(last_version));
+        self.notifications
+            .push(Notification::Erased(user_addr.clone()));
+        Ok(ids.len())
+    }
+
+    fn history(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        let before = after_id.unwrap_or(i64::MAX);
+        Ok(self
+            .history
+            .range(..before)
+            .rev()
+            .map(|(_, r)| r)
+            .filter(|r| &r.user_addr == user_addr && r.key == key)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn get_revision(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        id: i64,
+    ) -> Result<Option<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        Ok(self
+            .history
+            .get(&id)
+            .filter(|r| &r.user_addr == user_addr && r.key == key)
+            .cloned())
+    }
+
+    fn get_at(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        at: DateTime<Utc>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        let revision = self
+            .history
+            .values()
+            .rev()
+            .find(|r| &r.user_addr == user_addr && r.key == key && r.created_at <= at);
+
+        Ok(revision
+            .cloned()
+            .and_then(UserStorageRevision::entry)
+            .filter(|e| !is_expired(e, at)))
+    }
+
+    fn usage(&mut self, user_addr: &UserAddress) -> Result<UserStorageUsage, Error> {
+        Ok(self
+            .user_entries(user_addr)
+            .fold(UserStorageUsage::default(), |usage, entry| {
+                UserStorageUsage {
+                    entry_count: usage.entry_count + 1,
+                    total_bytes: usage.total_bytes + entry_size(entry),
+                }
+            }))
+    }
+
+    fn list_users(
+        &mut self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<(UserAddress, UserStorageUsage)>, Error> {
+        let mut users = BTreeMap::<&UserAddress, UserStorageUsage>::new();
+        for ((user_addr, _), entry) in self.entries.iter() {
+            if is_after(user_addr, after) {
+                let usage = users.entry(user_addr).or_default();
+                usage.entry_count += 1;
+                usage.total_bytes += entry_size(entry);
+            }
+        }
+
+        Ok(users
+            .into_iter()
+            .take(limit as usize)
+            .map(|(user_addr, usage)| (user_addr.clone(), usage))
+            .collect())
+    }
+
+    fn delete_expired(&mut self, limit: i64) -> Result<usize, Error> {
+        let now = Utc::now();
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, e)| is_expired(e, now))
+            .map(|(id, _)| id.clone())
+            .take(limit as usize)
+            .collect::<Vec<_>>();
+        for id in expired.iter() {
+            self.put_entry(id.clone(), None);
+        }
+        Ok(expired.len())
+    }
+
+    fn publish(&mut self, user_addr: &UserAddress, changes: &[Change]) -> Result<(), Error> {
+        let now = Utc::now();
+        for change in changes {
+            let change = NewUserStorageChange::from((user_addr.clone(), change.clone()));
+            self.last_change_id += 1;
+            let id = self.last_change_id;
+            self.put_change(
+                id,
+                Some(UserStorageChange {
+                    id,
+                    user_addr: change.user_addr,
+                    key: change.key,
+                    entry: change.entry,
+                    version: change.version,
+                    created_at: now,
+                }),
+            );
+        }
+        self.notifications
+            .push(Notification::Changed(user_addr.clone()));
+        Ok(())
+    }
+
+    fn changes_since(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<UserStorageChange>, Error> {
+        Ok(self
+            .changes
+            .range(after_id + 1..)
+            .map(|(_, c)| c)
+            .filter(|c| &c.user_addr == user_addr)
+            .filter(|c| c.key.starts_with(prefix))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn last_change_id(&mut self, user_addr: &UserAddress) -> Result<i64, Error> {
+        Ok(self
+            .changes
+            .values()
+            .rev()
+            .find(|c| &c.user_addr == user_addr)
+            .map(|c| c.id)
+            .unwrap_or_default())
+    }
+
+    fn delete_changes_before(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        let expired = self
+            .changes
+            .values()
+            .filter(|c| c.created_at < before)
+            .map(|c| c.id)
+            .take(limit as usize)
+            .collect::<Vec<_>>();
+        for id in expired.iter() {
+            self.put_change(*id, None);
+        }
+        Ok(expired.len())
+    }
+}
+
+impl MemStorage {
+    fn user_entries<'a>(
+        &'a self,
+        user_addr: &'a str,
+    ) -> impl Iterator<Item = &'a UserStorageEntry> + 'a {
+        self.entries
+            .range((user_addr.to_string(), String::new())..)
+            .take_while(move |((addr, _), _)| addr == user_addr)
+            .map(|(_, entry)| entry)
+    }
+
+    fn live_entry(&self, user_addr: &str, key: &str) -> Option<&UserStorageEntry> {
+        self.entries
+            .get(&(user_addr.to_string(), key.to_string()))
+            .filter(|e| !is_expired(e, Utc::now()))
+    }
+
+    /// Same as `ON CONFLICT DO UPDATE`, the version of an existing entry is incremented.
+    fn upsert(&mut self, entry: &UserStorageEntry) -> i64 {
+        let id = (entry.user_addr.clone(), entry.key.clone());
+        let version = match self.entries.get(&id) {
+            Some(old) => old.version + 1,
+            None => self.next_version(&entry.user_addr, &entry.key),
+        };
+        let entry = UserStorageEntry {
+            version,
+            ..entry.clone()
+        };
+
+        self.record_history(NewUserStorageRevision::from(&entry));
+        self.put_entry(id, Some(entry));
+        version
+    }
+
+    /// The version of a new entry follows the last recorded one of the key,
+    /// or the version floor of the user if higher, so that a deleted or erased
+    /// and re-created key never repeats its versions.
+    fn next_version(&self, user_addr: &str, key: &str) -> i64 {
+        let last_version = self
+            .history
+            .values()
+            .rev()
+            .find(|r| r.user_addr == user_addr && r.key == key)
+            .map_or(0, |r| r.version);
+        last_version.max(self.version_floor(user_addr)) + 1
+    }
+
+    fn version_floor(&self, user_addr: &str) -> i64 {
+        self.version_floors
+            .get(user_addr)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn delete_if_expired(&mut self, user_addr: &str, key: &str) {
+        let id = (user_addr.to_string(), key.to_string());
+        if matches!(self.entries.get(&id), Some(e) if is_expired(e, Utc::now())) {
+            self.put_entry(id, None);
+        }
+    }
+
+    fn record_history(&mut self, revision: NewUserStorageRevision) {
+        self.last_revision_id += 1;
+        let id = self.last_revision_id;
+        self.put_revision(
+            id,
+            Some(UserStorageRevision {
+                id,
+                user_addr: revision.user_addr,
+                key: revision.key,
+                entry_type: revision.entry_type,
+                entry_value_binary: revision.entry_value_binary,
+                entry_value_boolean: revision.entry_value_boolean,
+                entry_value_integer: revision.entry_value_integer,
+                entry_value_json: revision.entry_value_json,
+                entry_value_string: revision.entry_value_string,
+                version: revision.version,
+                expires_at: revision.expires_at,
+                created_at: Utc::now(),
+            }),
+        );
+    }
+
+    fn begin(&mut self) {
+        self.transaction = Some(Transaction {
+            undo: vec![],
+            last_revision_id: self.last_revision_id,
+            last_change_id: self.last_change_id,
+        });
+    }
+
+    fn commit(&mut self) {
+        self.transaction = None;
+    }
+
+    /// Undoes the writes of the transaction, the latest first.
+    fn rollback(&mut self) {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        for undo in transaction.undo.into_iter().rev() {
+            match undo {
+                Undo::Entry(id, entry) => {
+                    self.put_entry(id, entry);
+                }
+                Undo::Revision(id, revision) => {
+                    self.put_revision(id, revision);
+                }
+                Undo::Change(id, change) => {
+                    self.put_change(id, change);
+                }
+                Undo::VersionFloor(user_addr, floor) => {
+                    self.put_version_floor(user_addr, floor);
+                }
+            }
+        }
+        self.last_revision_id = transaction.last_revision_id;
+        self.last_change_id = transaction.last_change_id;
+        self.notifications.clear();
+    }
+
+    /// Writes or removes the entry, returning the previous one.
+    fn put_entry(
+        &mut self,
+        id: (UserAddress, String),
+        entry: Option<UserStorageEntry>,
+    ) -> Option<UserStorageEntry> {
+        let old = match entry {
+            Some(entry) => self.entries.insert(id.clone(), entry),
+            None => self.entries.remove(&id),
+        };
+        self.log(|| Undo::Entry(id, old.clone()));
+        old
+    }
+
+    fn put_revision(
+        &mut self,
+        id: i64,
+        revision: Option<UserStorageRevision>,
+    ) -> Option<UserStorageRevision> {
+        let old = match revision {
+            Some(revision) => self.history.insert(id, revision),
+            None => self.history.remove(&id),
+        };
+        self.log(|| Undo::Revision(id, old.clone()));
+        old
+    }
+
+    fn put_change(
+        &mut self,
+        id: i64,
+        change: Option<UserStorageChange>,
+    ) -> Option<UserStorageChange> {
+        let old = match change {
+            Some(change) => self.changes.insert(id, change),
+            None => self.changes.remove(&id),
+        };
+        self.log(|| Undo::Change(id, old.clone()));
+        old
+    }
+
+    fn put_version_floor(&mut self, user_addr: UserAddress, floor: Option<i64>) {
+        let old = match floor {
+            Some(floor) => self.version_floors.insert(user_addr.clone(), floor),
+            None => self.version_floors.remove(&user_addr),
+        };
+        self.log(|| Undo::VersionFloor(user_addr, old));
+    }
+
+    /// Logs how to undo a write, if made in a transaction.
+    fn log(&mut self, undo: impl FnOnce() -> Undo) {
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.undo.push(undo());
+        }
+    }
+}
+
+/// Whether the key (or the address) is past the cursor, if there is one.
+fn is_after(key: &str, after: Option<&str>) -> bool {
+    match after {
+        Some(after) => key > after,
+        None => true,
+    }
+}
+
+fn is_expired(entry: &UserStorageEntry, now: DateTime<Utc>) -> bool {
+    matches!(entry.expires_at, Some(expires_at) if expires_at <= now)
+}
+
+/// Same as the `user_storage_entry_size` SQL function,
+/// except that json values are measured in their compact form.
+fn entry_size(entry: &UserStorageEntry) -> i64 {
+    let value_size = match entry {
+        UserStorageEntry {
+            entry_value_binary: Some(value),
+            ..
+        }
+        | UserStorageEntry {
+            entry_value_string: Some(value),
+            ..
+        } => value.len(),
+        UserStorageEntry {
+            entry_value_json: Some(value),
+            ..
+        } => value.to_string().len(),
+        UserStorageEntry {
+            entry_value_integer: Some(_),
+            ..
+        } => 8,
+        UserStorageEntry {
+            entry_value_boolean: Some(_),
+            ..
+        } => 1,
+        _ => 0,
+    };
+    (entry.key.len() + value_size) as i64
+}
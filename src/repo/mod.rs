@@ -1,26 +1,48 @@
+pub mod cache;
+pub mod memory;
 pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 use crate::error::Error;
-use crate::models::{UserAddress, UserStorageEntry};
+use crate::models::{
+    dto::Change, UserAddress, UserStorageChange, UserStorageEntry, UserStorageRevision,
+    UserStorageUsage,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
 
 pub trait Key: ToString + Send + Sync {}
 impl<K: ToString + Send + Sync> Key for K {}
 
 #[async_trait]
 pub trait Repo: Send + Sync + 'static {
-    type Operations: RepoOperations;
+    /// The operations an interaction is run with, which may borrow for as long as `'a`.
+    type Operations<'a>: RepoOperations;
 
     async fn interact<F, R>(&self, f: F) -> Result<R, Error>
     where
-        F: FnOnce(&mut Self::Operations) -> Result<R, Error>,
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
         F: Send + Sync + 'static,
         R: Send + Sync + 'static;
 
     async fn transaction<F, R>(&self, f: F) -> Result<R, Error>
     where
-        F: FnOnce(&mut Self::Operations) -> Result<R, Error>,
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
         F: Send + Sync + 'static,
         R: Send + Sync + 'static;
+
+    /// Runs the interaction like `interact`, telling also whether any of the entries read
+    /// were served from a possibly stale copy, the storage having turned out unavailable.
+    async fn interact_tracking_stale<F, R>(&self, f: F) -> Result<(R, bool), Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        Ok((self.interact(f).await?, false))
+    }
 }
 
 pub trait RepoOperations {
@@ -36,9 +58,193 @@ pub trait RepoOperations {
         keys: &[impl Key],
     ) -> Result<Vec<UserStorageEntry>, Error>;
 
-    fn set(&mut self, entry: &UserStorageEntry) -> Result<(), Error>;
+    /// Same as `get`, but narrows a json value down to the given path, e.g. `["layout", "columns"]`.
+    /// The json value of the returned entry is `None` if there is nothing at that path.
+    fn get_path(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        path: &[String],
+    ) -> Result<Option<UserStorageEntry>, Error>;
+
+    /// Same as `mget`, but returns at most `limit` found entries ordered by key,
+    /// starting right after the `after` key.
+    fn mget_page(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error>;
+
+    fn scan_prefix(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error>;
+
+    /// Same as `mget`, but also locks the found rows until the end of the transaction.
+    fn mget_for_update(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error>;
+
+    /// Upserts the entry, returning its new version.
+    /// The version of the given entry is ignored: an existing entry gets its version incremented,
+    /// a new one continues from the last version recorded in the history of the key.
+    fn set(&mut self, entry: &UserStorageEntry) -> Result<i64, Error>;
+
+    /// Inserts the entry only if the key doesn't exist yet, returning its version.
+    /// The version is assigned the same way as by `set`.
+    fn insert(&mut self, entry: &UserStorageEntry) -> Result<Option<i64>, Error>;
+
+    /// Upserts the entries, returning their keys with new versions assigned as by `set`.
+    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<Vec<(String, i64)>, Error>;
 
-    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<(), Error>;
+    /// Atomically adds `delta` to an integer entry, creating it if absent,
+    /// and clamps the result to the given bounds.
+    /// Returns `None` if the existing entry is not an integer,
+    /// and a validation error of `delta` if the result doesn't fit into the integer range.
+    fn incr(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<Option<UserStorageEntry>, Error>;
+
+    /// Deletes the entries, returning the deleted keys with their last versions.
+    fn mdel(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<(String, i64)>, Error>;
+
+    /// Deletes all the entries of the user along with their history and recorded changes,
+    /// returning the number of deleted entries, and notifies the subscribers of the erasure
+    /// without recording any change. Only the highest version the user has reached is kept,
+    /// as the floor of the versions of new entries, so that no old version is ever repeated.
+    fn delete_all(&mut self, user_addr: &UserAddress) -> Result<usize, Error>;
+
+    /// Returns at most `limit` revisions of the entry, the most recent first,
+    /// starting right after the `after_id` one.
+    fn history(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageRevision>, Error>;
+
+    fn get_revision(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        id: i64,
+    ) -> Result<Option<UserStorageRevision>, Error>;
+
+    /// Returns the entry as it was at the given moment, judging by its history.
+    fn get_at(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        at: DateTime<Utc>,
+    ) -> Result<Option<UserStorageEntry>, Error>;
+
+    /// Returns the usage of the user, including the writes made in the current transaction.
+    fn usage(&mut self, user_addr: &UserAddress) -> Result<UserStorageUsage, Error>;
+
+    /// Returns at most `limit` users having entries with their usage, ordered by address,
+    /// starting right after the `after` address.
+    fn list_users(
+        &mut self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<(UserAddress, UserStorageUsage)>, Error>;
+
+    /// Removes at most `limit` expired entries, returning the number of removed ones.
+    /// Expiration is not recorded in the history, the revisions keep the expiration time instead.
+    fn delete_expired(&mut self, limit: i64) -> Result<usize, Error>;
+
+    /// Records the changes and notifies the change feed subscribers of the user.
+    /// Expected to be called in a transaction, the changes of a user get ids
+    /// increasing in the order their transactions commit.
+    fn publish(&mut self, user_addr: &UserAddress, changes: &[Change]) -> Result<(), Error>;
+
+    /// Returns at most `limit` changes made after the `after_id` one to the keys starting with `prefix`.
+    fn changes_since(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<UserStorageChange>, Error>;
+
+    /// Returns the id of the latest recorded change of the user, or 0 if there is none.
+    fn last_change_id(&mut self, user_addr: &UserAddress) -> Result<i64, Error>;
+
+    /// Removes at most `limit` changes recorded before the given time,
+    /// returning the number of removed ones.
+    fn delete_changes_before(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error>;
+}
+
+/// Adds `delta` to the value and clamps the sum to the given bounds, the same as
+/// `LEAST(GREATEST(value + delta, min), max)` evaluated in `numeric`.
+/// Fails only if the clamped sum doesn't fit into the integer range.
+fn clamped_sum(value: i64, delta: i64, min: Option<i64>, max: Option<i64>) -> Result<i64, Error> {
+    let sum = i128::from(value) + i128::from(delta);
+    let sum = min.map_or(sum, |min| sum.max(min.into()));
+    let sum = max.map_or(sum, |max| sum.min(max.into()));
+    i64::try_from(sum).map_err(|_| out_of_range(delta))
+}
+
+fn out_of_range(delta: i64) -> Error {
+    Error::ValidationError(
+        "delta".to_string(),
+        Some(HashMap::from([
+            ("delta".to_string(), delta.to_string()),
+            (
+                "reason".to_string(),
+                "the result is out of the integer range".to_string(),
+            ),
+        ])),
+    )
+}
+
+/// Builds a `LIKE` pattern matching every string starting with `prefix`,
+/// escaping the pattern metacharacters that may occur in the prefix itself.
+fn like_prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
 
-    fn mdel(&mut self, user_addr: &UserAddress, keys: &[impl Key]) -> Result<(), Error>;
+/// Same as the jsonb `#>` operator, array elements are addressed by their index,
+/// negative ones counting from the end.
+fn json_at<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, element| match value {
+        Value::Object(members) => members.get(element),
+        Value::Array(items) => {
+            let index = element.parse::<i64>().ok()?;
+            let index = if index < 0 {
+                items.len() as i64 + index
+            } else {
+                index
+            };
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| items.get(index))
+        }
+        _ => None,
+    })
 }
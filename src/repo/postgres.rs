@@ -1,9 +1,21 @@
-use super::{Key, Repo, RepoOperations};
+use super::{like_prefix_pattern, out_of_range, Key, Repo, RepoOperations};
+use crate::changes::{CHANNEL, ERASURES_CHANNEL};
 use crate::db::PgAsyncPool;
 use crate::error::Error;
-use crate::models::{UserAddress, UserStorageEntry};
+use crate::models::{
+    dto::Change, NewUserStorageChange, NewUserStorageRevision, UserAddress, UserStorageChange,
+    UserStorageEntry, UserStorageRevision, UserStorageUsage,
+};
 use crate::schema::*;
-use diesel::{prelude::*, upsert::excluded, PgConnection};
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::{self, now},
+    prelude::*,
+    sql_types::{Array, BigInt, Jsonb, Nullable, Text, Timestamptz},
+    upsert::excluded,
+    PgConnection,
+};
+use std::collections::HashMap;
 use wavesexchange_repos::CircuitBreaker;
 
 pub struct PgRepo {
@@ -12,11 +24,11 @@ pub struct PgRepo {
 
 #[async_trait]
 impl Repo for PgRepo {
-    type Operations = PgConnection;
+    type Operations<'a> = PgConnection;
 
     async fn interact<F, R>(&self, f: F) -> Result<R, Error>
     where
-        F: FnOnce(&mut Self::Operations) -> Result<R, Error>,
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
         F: Send + Sync + 'static,
         R: Send + Sync + 'static,
     {
@@ -30,7 +42,7 @@ impl Repo for PgRepo {
 
     async fn transaction<F, R>(&self, f: F) -> Result<R, Error>
     where
-        F: FnOnce(&mut Self::Operations) -> Result<R, Error>,
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
         F: Send + Sync + 'static,
         R: Send + Sync + 'static,
     {
@@ -59,6 +71,7 @@ impl RepoOperations for PgConnection {
         user_storage::table
             .filter(user_storage::user_addr.eq(user_addr))
             .filter(user_storage::key.eq(key))
+            .filter(not_expired())
             .first(self)
             .optional()
             .map_err(Error::from)
@@ -73,24 +86,148 @@ impl RepoOperations for PgConnection {
         user_storage::table
             .filter(user_storage::user_addr.eq(user_addr))
             .filter(user_storage::key.eq_any(keys))
+            .filter(not_expired())
             .load(self)
             .map_err(Error::from)
     }
 
-    fn set(&mut self, entry: &UserStorageEntry) -> Result<(), Error> {
-        diesel::insert_into(user_storage::table)
-            .values(entry)
+    fn get_path(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        path: &[String],
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        user_storage::table
+            .select((
+                user_storage::key,
+                user_storage::user_addr,
+                user_storage::entry_type,
+                user_storage::entry_value_binary,
+                user_storage::entry_value_boolean,
+                user_storage::entry_value_integer,
+                dsl::sql::<Nullable<Jsonb>>("entry_value_json #> ")
+                    .bind::<Array<Text>, _>(path.to_vec()),
+                user_storage::entry_value_string,
+                user_storage::version,
+                user_storage::expires_at,
+            ))
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq(key))
+            .filter(not_expired())
+            .first(self)
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn mget_page(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        let mut query = user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq_any(keys))
+            .filter(not_expired())
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(user_storage::key.gt(after));
+        }
+        query
+            .order(user_storage::key.asc())
+            .limit(limit)
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn scan_prefix(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let mut query = user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(
+                user_storage::key
+                    .like(like_prefix_pattern(prefix))
+                    .escape('\\'),
+            )
+            .filter(not_expired())
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(user_storage::key.gt(after));
+        }
+        query
+            .order(user_storage::key.asc())
+            .limit(limit)
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn mget_for_update(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq_any(keys))
+            .filter(not_expired())
+            .for_update()
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn set(&mut self, entry: &UserStorageEntry) -> Result<i64, Error> {
+        let entry = diesel::insert_into(user_storage::table)
+            .values(with_next_versions(self, std::slice::from_ref(entry))?)
             .on_conflict((user_storage::key, user_storage::user_addr))
             .do_update()
-            .set(entry)
-            .execute(self)
-            .map_err(Error::from)?;
-        Ok(())
+            .set((
+                user_storage::entry_type.eq(excluded(user_storage::entry_type)),
+                user_storage::entry_value_binary.eq(excluded(user_storage::entry_value_binary)),
+                user_storage::entry_value_boolean.eq(excluded(user_storage::entry_value_boolean)),
+                user_storage::entry_value_integer.eq(excluded(user_storage::entry_value_integer)),
+                user_storage::entry_value_json.eq(excluded(user_storage::entry_value_json)),
+                user_storage::entry_value_string.eq(excluded(user_storage::entry_value_string)),
+                user_storage::version.eq(user_storage::version + 1),
+                user_storage::expires_at.eq(excluded(user_storage::expires_at)),
+            ))
+            .get_result::<UserStorageEntry>(self)?;
+
+        record_history(self, &[NewUserStorageRevision::from(&entry)])?;
+        Ok(entry.version)
+    }
+
+    fn insert(&mut self, entry: &UserStorageEntry) -> Result<Option<i64>, Error> {
+        // an expired entry is as good as absent, but it would still conflict with the new one
+        delete_if_expired(self, &entry.user_addr, &entry.key)?;
+
+        let inserted = diesel::insert_into(user_storage::table)
+            .values(with_next_versions(self, std::slice::from_ref(entry))?)
+            .on_conflict((user_storage::key, user_storage::user_addr))
+            .do_nothing()
+            .get_result::<UserStorageEntry>(self)
+            .optional()?;
+
+        match inserted {
+            Some(inserted) => {
+                record_history(self, &[NewUserStorageRevision::from(&inserted)])?;
+                Ok(Some(inserted.version))
+            }
+            None => Ok(None),
+        }
     }
 
-    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<(), Error> {
-        diesel::insert_into(user_storage::table)
-            .values(entries)
+    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<Vec<(String, i64)>, Error> {
+        let entries = diesel::insert_into(user_storage::table)
+            .values(with_next_versions(self, entries)?)
             .on_conflict((user_storage::key, user_storage::user_addr))
             .do_update()
             .set((
@@ -100,21 +237,405 @@ impl RepoOperations for PgConnection {
                 user_storage::entry_value_integer.eq(excluded(user_storage::entry_value_integer)),
                 user_storage::entry_value_json.eq(excluded(user_storage::entry_value_json)),
                 user_storage::entry_value_string.eq(excluded(user_storage::entry_value_string)),
+                user_storage::version.eq(user_storage::version + 1),
+                user_storage::expires_at.eq(excluded(user_storage::expires_at)),
             ))
-            .execute(self)
-            .map_err(Error::from)?;
-        Ok(())
+            .get_results::<UserStorageEntry>(self)?;
+
+        let revisions = entries
+            .iter()
+            .map(NewUserStorageRevision::from)
+            .collect::<Vec<_>>();
+        record_history(self, &revisions)?;
+
+        Ok(entries.into_iter().map(|e| (e.key, e.version)).collect())
+    }
+
+    fn incr(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        delete_if_expired(self, user_addr, &key)?;
+
+        // GREATEST/LEAST ignore NULLs, so missing bounds don't affect the value,
+        // and the sum is clamped in numeric, so only a result out of bounds can't fit into bigint
+        let entry = diesel::sql_query(
+            "INSERT INTO user_storage (key, user_addr, entry_type, entry_value_integer, version)
+            VALUES ($1, $2, 'integer', LEAST(GREATEST($3, $4), $5), GREATEST(
+                (SELECT max(version) FROM user_storage_history WHERE user_addr = $2 AND key = $1),
+                (SELECT version_floor FROM user_storage_usage WHERE user_addr = $2),
+                0
+            ) + 1)
+            ON CONFLICT (key, user_addr) DO UPDATE
+            SET entry_value_integer =
+                    LEAST(GREATEST(user_storage.entry_value_integer::numeric + $3, $4), $5)::bigint,
+                version = user_storage.version + 1
+            WHERE user_storage.entry_type = 'integer'
+                AND LEAST(GREATEST(user_storage.entry_value_integer::numeric + $3, $4), $5)
+                    BETWEEN $6 AND $7
+            RETURNING *",
+        )
+        .bind::<Text, _>(&key)
+        .bind::<Text, _>(user_addr)
+        .bind::<BigInt, _>(delta)
+        .bind::<Nullable<BigInt>, _>(min)
+        .bind::<Nullable<BigInt>, _>(max)
+        .bind::<BigInt, _>(i64::MIN)
+        .bind::<BigInt, _>(i64::MAX)
+        .get_result::<UserStorageEntry>(self)
+        .optional()?;
+
+        match &entry {
+            Some(entry) => record_history(self, &[NewUserStorageRevision::from(entry)])?,
+            // the existing entry is left as it is either if it is not an integer
+            // or if the result doesn't fit
+            None => {
+                let entry_type = user_storage::table
+                    .select(user_storage::entry_type)
+                    .filter(user_storage::user_addr.eq(user_addr))
+                    .filter(user_storage::key.eq(&key))
+                    .first::<String>(self)?;
+                if entry_type == "integer" {
+                    return Err(out_of_range(delta));
+                }
+            }
+        }
+        Ok(entry)
     }
 
-    fn mdel(&mut self, user_addr: &UserAddress, keys: &[impl Key]) -> Result<(), Error> {
+    fn mdel(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<(String, i64)>, Error> {
         let keys = keys.into_iter().map(|k| k.to_string()).collect::<Vec<_>>();
-        diesel::delete(
+        let deleted = diesel::delete(
             user_storage::table
                 .filter(user_storage::user_addr.eq(user_addr))
                 .filter(user_storage::key.eq_any(keys)),
         )
+        .returning((user_storage::key, user_storage::version))
+        .get_results::<(String, i64)>(self)?;
+
+        let revisions = deleted
+            .iter()
+            .map(|(key, version)| {
+                NewUserStorageRevision::deleted(user_addr.clone(), key.clone(), *version)
+            })
+            .collect::<Vec<_>>();
+        record_history(self, &revisions)?;
+
+        Ok(deleted)
+    }
+
+    fn delete_all(&mut self, user_addr: &UserAddress) -> Result<usize, Error> {
+        // the entries may predate the history, so their versions are counted as well
+        let last_version = user_storage_history::table
+            .select(dsl::max(user_storage_history::version))
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .first::<Option<i64>>(self)?;
+        let last_entry_version = user_storage::table
+            .select(dsl::max(user_storage::version))
+            .filter(user_storage::user_addr.eq(user_addr))
+            .first::<Option<i64>>(self)?;
+
+        let deleted =
+            diesel::delete(user_storage::table.filter(user_storage::user_addr.eq(user_addr)))
+                .execute(self)?;
+        diesel::delete(
+            user_storage_history::table.filter(user_storage_history::user_addr.eq(user_addr)),
+        )
+        .execute(self)?;
+        diesel::delete(
+            user_storage_changes::table.filter(user_storage_changes::user_addr.eq(user_addr)),
+        )
+        .execute(self)?;
+        // the usage row is left to the triggers, only the floor of the versions is raised
+        diesel::insert_into(user_storage_usage::table)
+            .values((
+                user_storage_usage::user_addr.eq(user_addr),
+                user_storage_usage::version_floor
+                    .eq(last_version.max(last_entry_version).unwrap_or_default()),
+            ))
+            .on_conflict(user_storage_usage::user_addr)
+            .do_update()
+            .set(user_storage_usage::version_floor.eq(dsl::sql::<BigInt>(
+                "GREATEST(user_storage_usage.version_floor, excluded.version_floor)",
+            )))
+            .execute(self)?;
+
+        notify(self, ERASURES_CHANNEL, user_addr)?;
+        Ok(deleted)
+    }
+
+    fn history(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        let mut query = user_storage_history::table
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .into_boxed();
+        if let Some(after_id) = after_id {
+            query = query.filter(user_storage_history::id.lt(after_id));
+        }
+        query
+            .order(user_storage_history::id.desc())
+            .limit(limit)
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn get_revision(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        id: i64,
+    ) -> Result<Option<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        user_storage_history::table
+            .filter(user_storage_history::id.eq(id))
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .first(self)
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn get_at(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        at: DateTime<Utc>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        let revision = user_storage_history::table
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .filter(user_storage_history::created_at.le(at))
+            .order(user_storage_history::id.desc())
+            .first::<UserStorageRevision>(self)
+            .optional()?;
+
+        Ok(revision
+            .and_then(UserStorageRevision::entry)
+            .filter(|e| match e.expires_at {
+                Some(expires_at) => expires_at > at,
+                None => true,
+            }))
+    }
+
+    fn usage(&mut self, user_addr: &UserAddress) -> Result<UserStorageUsage, Error> {
+        user_storage_usage::table
+            .select((
+                user_storage_usage::entry_count,
+                user_storage_usage::total_bytes,
+            ))
+            .filter(user_storage_usage::user_addr.eq(user_addr))
+            .first(self)
+            .optional()
+            .map(Option::unwrap_or_default)
+            .map_err(Error::from)
+    }
+
+    fn list_users(
+        &mut self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<(UserAddress, UserStorageUsage)>, Error> {
+        let mut query = user_storage_usage::table
+            .select((
+                user_storage_usage::user_addr,
+                (
+                    user_storage_usage::entry_count,
+                    user_storage_usage::total_bytes,
+                ),
+            ))
+            .filter(user_storage_usage::entry_count.gt(0))
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(user_storage_usage::user_addr.gt(after));
+        }
+        query
+            .order(user_storage_usage::user_addr.asc())
+            .limit(limit)
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn delete_expired(&mut self, limit: i64) -> Result<usize, Error> {
+        diesel::sql_query(
+            "DELETE FROM user_storage WHERE ctid IN (
+                SELECT ctid FROM user_storage WHERE expires_at <= now() LIMIT $1
+            )",
+        )
+        .bind::<BigInt, _>(limit)
+        .execute(self)
+        .map_err(Error::from)
+    }
+
+    fn publish(&mut self, user_addr: &UserAddress, changes: &[Change]) -> Result<(), Error> {
+        // ids are taken from a sequence, so the transactions of the user have to commit
+        // in the order of their ids, lest a follower past a later id misses an earlier one
+        diesel::sql_query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind::<Text, _>(user_addr)
+            .execute(self)?;
+
+        let changes = changes
+            .iter()
+            .map(|c| NewUserStorageChange::from((user_addr.clone(), c.clone())))
+            .collect::<Vec<_>>();
+        diesel::insert_into(user_storage_changes::table)
+            .values(&changes)
+            .execute(self)?;
+
+        notify(self, CHANNEL, user_addr)
+    }
+
+    fn changes_since(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<UserStorageChange>, Error> {
+        user_storage_changes::table
+            .filter(user_storage_changes::user_addr.eq(user_addr))
+            .filter(user_storage_changes::id.gt(after_id))
+            .filter(
+                user_storage_changes::key
+                    .like(like_prefix_pattern(prefix))
+                    .escape('\\'),
+            )
+            .order(user_storage_changes::id.asc())
+            .limit(limit)
+            .load(self)
+            .map_err(Error::from)
+    }
+
+    fn last_change_id(&mut self, user_addr: &UserAddress) -> Result<i64, Error> {
+        user_storage_changes::table
+            .select(dsl::max(user_storage_changes::id))
+            .filter(user_storage_changes::user_addr.eq(user_addr))
+            .first::<Option<i64>>(self)
+            .map(Option::unwrap_or_default)
+            .map_err(Error::from)
+    }
+
+    fn delete_changes_before(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        diesel::sql_query(
+            "DELETE FROM user_storage_changes WHERE id IN (
+                SELECT id FROM user_storage_changes WHERE created_at < $1 LIMIT $2
+            )",
+        )
+        .bind::<Timestamptz, _>(before)
+        .bind::<BigInt, _>(limit)
         .execute(self)
-        .map_err(Error::from)?;
-        Ok(())
+        .map_err(Error::from)
+    }
+}
+
+fn record_history(
+    conn: &mut PgConnection,
+    revisions: &[NewUserStorageRevision],
+) -> Result<(), Error> {
+    if !revisions.is_empty() {
+        diesel::insert_into(user_storage_history::table)
+            .values(revisions)
+            .execute(conn)?;
     }
+    Ok(())
+}
+
+/// Gives the entries the versions following the last recorded ones of their keys,
+/// or the version floors of their users if higher, so that a deleted or erased
+/// and re-created key never repeats its versions.
+/// The existing entries get their versions incremented on conflict instead.
+fn with_next_versions(
+    conn: &mut PgConnection,
+    entries: &[UserStorageEntry],
+) -> Result<Vec<UserStorageEntry>, Error> {
+    let user_addrs = entries.iter().map(|e| &e.user_addr).collect::<Vec<_>>();
+    let keys = entries.iter().map(|e| &e.key).collect::<Vec<_>>();
+    let last_versions = user_storage_history::table
+        .filter(user_storage_history::user_addr.eq_any(&user_addrs))
+        .filter(user_storage_history::key.eq_any(keys))
+        .group_by((user_storage_history::user_addr, user_storage_history::key))
+        .select((
+            user_storage_history::user_addr,
+            user_storage_history::key,
+            dsl::max(user_storage_history::version),
+        ))
+        .load::<(String, String, Option<i64>)>(conn)?
+        .into_iter()
+        .filter_map(|(user_addr, key, version)| Some(((user_addr, key), version?)))
+        .collect::<HashMap<_, _>>();
+    let version_floors = user_storage_usage::table
+        .filter(user_storage_usage::user_addr.eq_any(&user_addrs))
+        .select((
+            user_storage_usage::user_addr,
+            user_storage_usage::version_floor,
+        ))
+        .load::<(String, i64)>(conn)?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    Ok(entries
+        .iter()
+        .map(|entry| {
+            let last_version = last_versions
+                .get(&(entry.user_addr.clone(), entry.key.clone()))
+                .copied()
+                .unwrap_or_default();
+            let version_floor = version_floors
+                .get(&entry.user_addr)
+                .copied()
+                .unwrap_or_default();
+            UserStorageEntry {
+                version: last_version.max(version_floor) + 1,
+                ..entry.clone()
+            }
+        })
+        .collect())
+}
+
+fn delete_if_expired(conn: &mut PgConnection, user_addr: &str, key: &str) -> Result<(), Error> {
+    diesel::delete(
+        user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq(key))
+            .filter(user_storage::expires_at.le(now)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+type NotExpired = dsl::Or<
+    dsl::IsNull<user_storage::expires_at>,
+    dsl::Gt<dsl::AssumeNotNull<user_storage::expires_at>, now>,
+>;
+
+/// Filters out the entries which are past their expiration time.
+fn not_expired() -> NotExpired {
+    user_storage::expires_at
+        .is_null()
+        .or(user_storage::expires_at.assume_not_null().gt(now))
+}
+
+/// Postgres delivers the notification only once the transaction is committed.
+fn notify(conn: &mut PgConnection, channel: &str, user_addr: &UserAddress) -> Result<(), Error> {
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(channel)
+        .bind::<Text, _>(user_addr)
+        .execute(conn)?;
+    Ok(())
 }
@@ -0,0 +1,931 @@
+//! Repo backed by an embedded SQLite database, for the deployments without Postgres.
+//!
+//! SQLite allows a single writer at a time anyway, so the repo keeps a single connection,
+//! and the operations are run on it one after another off the async runtime.
+//! Timestamps are stored as milliseconds since the epoch, json values as text.
+
+use super::{clamped_sum, json_at, like_prefix_pattern, Key, Repo, RepoOperations};
+use crate::changes::{ChangeFeed, Notification};
+use crate::error::Error;
+use crate::models::{
+    dto::Change, NewUserStorageChange, NewUserStorageRevision, UserAddress, UserStorageChange,
+    UserStorageEntry, UserStorageRevision, UserStorageUsage,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::{
+    connection::{AnsiTransactionManager, SimpleConnection, TransactionManager},
+    dsl,
+    prelude::*,
+    sql_types::{BigInt, Text},
+    SqliteConnection,
+};
+use serde_json::Value;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+mod schema {
+    diesel::table! {
+        user_storage (key, user_addr) {
+            key -> Text,
+            user_addr -> Text,
+            entry_type -> Text,
+            entry_value_binary -> Nullable<Text>,
+            entry_value_boolean -> Nullable<Bool>,
+            entry_value_integer -> Nullable<BigInt>,
+            entry_value_json -> Nullable<Text>,
+            entry_value_string -> Nullable<Text>,
+            version -> BigInt,
+            expires_at -> Nullable<BigInt>,
+        }
+    }
+
+    diesel::table! {
+        user_storage_history (id) {
+            id -> BigInt,
+            user_addr -> Text,
+            key -> Text,
+            entry_type -> Nullable<Text>,
+            entry_value_binary -> Nullable<Text>,
+            entry_value_boolean -> Nullable<Bool>,
+            entry_value_integer -> Nullable<BigInt>,
+            entry_value_json -> Nullable<Text>,
+            entry_value_string -> Nullable<Text>,
+            version -> BigInt,
+            expires_at -> Nullable<BigInt>,
+            created_at -> BigInt,
+        }
+    }
+
+    diesel::table! {
+        user_storage_changes (id) {
+            id -> BigInt,
+            user_addr -> Text,
+            key -> Text,
+            entry -> Nullable<Text>,
+            version -> BigInt,
+            created_at -> BigInt,
+        }
+    }
+
+    diesel::table! {
+        user_storage_usage (user_addr) {
+            user_addr -> Text,
+            entry_count -> BigInt,
+            total_bytes -> BigInt,
+            version_floor -> BigInt,
+        }
+    }
+}
+
+use schema::*;
+
+/// The number of keys bound in a single `IN` list, SQLite limiting the bound variables
+/// of a statement to 999 before 3.32.
+const KEYS_PER_QUERY: usize = 500;
+
+/// `LIKE` is case-insensitive in SQLite by default, unlike in Postgres.
+const CONNECTION_SETUP: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA busy_timeout = 5000;
+    PRAGMA case_sensitive_like = ON;
+";
+
+pub struct SqliteRepo {
+    storage: Arc<Mutex<SqliteStorage>>,
+    changes: ChangeFeed,
+}
+
+pub struct SqliteStorage {
+    conn: SqliteConnection,
+    /// Users with changes published or erased since the last commit
+    notifications: Vec<Notification>,
+}
+
+#[async_trait]
+impl Repo for SqliteRepo {
+    type Operations<'a> = SqliteStorage;
+
+    async fn interact<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        let (storage, changes) = (self.storage.clone(), self.changes.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut storage = lock(&storage);
+            let result = f(&mut storage);
+            storage.notify(&changes);
+            result
+        })
+        .await
+        .expect("sqlite interaction panicked")
+    }
+
+    async fn transaction<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        let (storage, changes) = (self.storage.clone(), self.changes.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut storage = lock(&storage);
+            AnsiTransactionManager::begin_transaction(&mut storage.conn)?;
+            // left open, the transaction would turn all the later ones into savepoints
+            // never to be committed, so it's rolled back before the panic is propagated
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| f(&mut storage))) {
+                Ok(result) => result,
+                Err(panic) => {
+                    storage.notifications.clear();
+                    let _ = AnsiTransactionManager::rollback_transaction(&mut storage.conn);
+                    panic::resume_unwind(panic);
+                }
+            };
+            match result {
+                Ok(result) => {
+                    AnsiTransactionManager::commit_transaction(&mut storage.conn)?;
+                    storage.notify(&changes);
+                    Ok(result)
+                }
+                Err(e) => {
+                    storage.notifications.clear();
+                    AnsiTransactionManager::rollback_transaction(&mut storage.conn)?;
+                    Err(e)
+                }
+            }
+        })
+        .await
+        .expect("sqlite transaction panicked")
+    }
+}
+
+/// Opens the database at the given path, which is expected to be migrated already.
+pub fn new(path: &str, changes: ChangeFeed) -> Result<SqliteRepo, Error> {
+    let mut conn =
+        SqliteConnection::establish(path).map_err(|e| Error::GeneralError(e.to_string()))?;
+    conn.batch_execute(CONNECTION_SETUP)?;
+
+    Ok(SqliteRepo {
+        storage: Arc::new(Mutex::new(SqliteStorage {
+            conn,
+            notifications: vec![],
+        })),
+        changes,
+    })
+}
+
+fn lock(storage: &Mutex<SqliteStorage>) -> MutexGuard<'_, SqliteStorage> {
+    storage
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl RepoOperations for SqliteStorage {
+    fn get(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq(key))
+            .filter(not_expired())
+            .first::<EntryRow>(&mut self.conn)
+            .optional()?
+            .map(EntryRow::into_entry)
+            .transpose()
+    }
+
+    fn mget(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let mut entries = vec![];
+        for keys in distinct_keys(keys).chunks(KEYS_PER_QUERY) {
+            let rows = user_storage::table
+                .filter(user_storage::user_addr.eq(user_addr))
+                .filter(user_storage::key.eq_any(keys))
+                .filter(not_expired())
+                .load::<EntryRow>(&mut self.conn)?;
+            for row in rows {
+                entries.push(row.into_entry()?);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn get_path(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        path: &[String],
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        // there is no jsonb in SQLite, so the value is narrowed down here
+        Ok(self.get(user_addr, key)?.map(|entry| UserStorageEntry {
+            entry_value_json: entry
+                .entry_value_json
+                .as_ref()
+                .and_then(|value| json_at(value, path))
+                .cloned(),
+            ..entry
+        }))
+    }
+
+    fn mget_page(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        // the keys are sorted before being chunked, so the entries of a chunk precede
+        // the ones of the next chunk, which are only read if the page is not full yet
+        let mut entries = vec![];
+        for keys in distinct_keys(keys).chunks(KEYS_PER_QUERY) {
+            let mut query = user_storage::table
+                .filter(user_storage::user_addr.eq(user_addr))
+                .filter(user_storage::key.eq_any(keys))
+                .filter(not_expired())
+                .into_boxed();
+            if let Some(after) = after {
+                query = query.filter(user_storage::key.gt(after));
+            }
+            let rows = query
+                .order(user_storage::key.asc())
+                .limit(limit - entries.len() as i64)
+                .load::<EntryRow>(&mut self.conn)?;
+            for row in rows {
+                entries.push(row.into_entry()?);
+            }
+            if entries.len() as i64 >= limit {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn scan_prefix(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        let mut query = user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(
+                user_storage::key
+                    .like(like_prefix_pattern(prefix))
+                    .escape('\\'),
+            )
+            .filter(not_expired())
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(user_storage::key.gt(after));
+        }
+        query
+            .order(user_storage::key.asc())
+            .limit(limit)
+            .load::<EntryRow>(&mut self.conn)?
+            .into_iter()
+            .map(EntryRow::into_entry)
+            .collect()
+    }
+
+    fn mget_for_update(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<UserStorageEntry>, Error> {
+        // the connection is not shared, so nothing can change the rows meanwhile
+        self.mget(user_addr, keys)
+    }
+
+    fn set(&mut self, entry: &UserStorageEntry) -> Result<i64, Error> {
+        self.upsert(entry)
+    }
+
+    fn insert(&mut self, entry: &UserStorageEntry) -> Result<Option<i64>, Error> {
+        // an expired entry is as good as absent, but it would still conflict with the new one
+        self.delete_if_expired(&entry.user_addr, &entry.key)?;
+
+        if self.version(&entry.user_addr, &entry.key)?.is_some() {
+            return Ok(None);
+        }
+        let entry = UserStorageEntry {
+            version: self.next_version(&entry.user_addr, &entry.key)?,
+            ..entry.clone()
+        };
+        diesel::insert_into(user_storage::table)
+            .values(EntryRow::from_entry(&entry))
+            .execute(&mut self.conn)?;
+        self.record_history(NewUserStorageRevision::from(&entry))?;
+        Ok(Some(entry.version))
+    }
+
+    fn mset(&mut self, entries: &[UserStorageEntry]) -> Result<Vec<(String, i64)>, Error> {
+        entries
+            .iter()
+            .map(|entry| Ok((entry.key.clone(), self.upsert(entry)?)))
+            .collect()
+    }
+
+    fn incr(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        self.delete_if_expired(user_addr, &key)?;
+
+        let old_entry = user_storage::table
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq(&key))
+            .first::<EntryRow>(&mut self.conn)
+            .optional()?;
+
+        let entry = match old_entry {
+            Some(old) if old.entry_type != "integer" => return Ok(None),
+            Some(old) => {
+                let value =
+                    clamped_sum(old.entry_value_integer.unwrap_or_default(), delta, min, max)?;
+                diesel::update(
+                    user_storage::table
+                        .filter(user_storage::user_addr.eq(user_addr))
+                        .filter(user_storage::key.eq(&key)),
+                )
+                .set((
+                    user_storage::entry_value_integer.eq(value),
+                    user_storage::version.eq(old.version + 1),
+                ))
+                .execute(&mut self.conn)?;
+                EntryRow {
+                    entry_value_integer: Some(value),
+                    version: old.version + 1,
+                    ..old
+                }
+            }
+            None => {
+                let new = EntryRow {
+                    version: self.next_version(user_addr, &key)?,
+                    key,
+                    user_addr: user_addr.clone(),
+                    entry_type: "integer".to_string(),
+                    entry_value_binary: None,
+                    entry_value_boolean: None,
+                    entry_value_integer: Some(clamped_sum(0, delta, min, max)?),
+                    entry_value_json: None,
+                    entry_value_string: None,
+                    expires_at: None,
+                };
+                diesel::insert_into(user_storage::table)
+                    .values(&new)
+                    .execute(&mut self.conn)?;
+                new
+            }
+        };
+
+        let entry = entry.into_entry()?;
+        self.record_history(NewUserStorageRevision::from(&entry))?;
+        Ok(Some(entry))
+    }
+
+    fn mdel(
+        &mut self,
+        user_addr: &UserAddress,
+        keys: &[impl Key],
+    ) -> Result<Vec<(String, i64)>, Error> {
+        let mut deleted = vec![];
+        for keys in distinct_keys(keys).chunks(KEYS_PER_QUERY) {
+            let entries = user_storage::table
+                .filter(user_storage::user_addr.eq(user_addr))
+                .filter(user_storage::key.eq_any(keys));
+
+            deleted.extend(
+                entries
+                    .clone()
+                    .select((user_storage::key, user_storage::version))
+                    .load::<(String, i64)>(&mut self.conn)?,
+            );
+            diesel::delete(entries).execute(&mut self.conn)?;
+        }
+
+        for (key, version) in deleted.iter() {
+            self.record_history(NewUserStorageRevision::deleted(
+                user_addr.clone(),
+                key.clone(),
+                *version,
+            ))?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_all(&mut self, user_addr: &UserAddress) -> Result<usize, Error> {
+        // the entries may predate the history, so their versions are counted as well
+        let last_version = user_storage_history::table
+            .select(dsl::max(user_storage_history::version))
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .first::<Option<i64>>(&mut self.conn)?;
+        let last_entry_version = user_storage::table
+            .select(dsl::max(user_storage::version))
+            .filter(user_storage::user_addr.eq(user_addr))
+            .first::<Option<i64>>(&mut self.conn)?;
+
+        let deleted =
+            diesel::delete(user_storage::table.filter(user_storage::user_addr.eq(user_addr)))
+                .execute(&mut self.conn)?;
+        diesel::delete(
+            user_storage_history::table.filter(user_storage_history::user_addr.eq(user_addr)),
+        )
+        .execute(&mut self.conn)?;
+        diesel::delete(
+            user_storage_changes::table.filter(user_storage_changes::user_addr.eq(user_addr)),
+        )
+        .execute(&mut self.conn)?;
+        // the usage row is left to the triggers, only the floor of the versions is raised
+        diesel::sql_query(
+            "INSERT INTO user_storage_usage (user_addr, version_floor) VALUES (?, ?)
+            ON CONFLICT (user_addr) DO UPDATE
+            SET version_floor = max(version_floor, excluded.version_floor)",
+        )
+        .bind::<Text, _>(user_addr)
+        .bind::<BigInt, _>(last_version.max(last_entry_version).unwrap_or_default())
+        .execute(&mut self.conn)?;
+
+        self.notifications
+            .push(Notification::Erased(user_addr.clone()));
+        Ok(deleted)
+    }
+
+    fn history(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        let mut query = user_storage_history::table
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .into_boxed();
+        if let Some(after_id) = after_id {
+            query = query.filter(user_storage_history::id.lt(after_id));
+        }
+        query
+            .order(user_storage_history::id.desc())
+            .limit(limit)
+            .load::<RevisionRow>(&mut self.conn)?
+            .into_iter()
+            .map(RevisionRow::into_revision)
+            .collect()
+    }
+
+    fn get_revision(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        id: i64,
+    ) -> Result<Option<UserStorageRevision>, Error> {
+        let key = key.to_string();
+        user_storage_history::table
+            .filter(user_storage_history::id.eq(id))
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .first::<RevisionRow>(&mut self.conn)
+            .optional()?
+            .map(RevisionRow::into_revision)
+            .transpose()
+    }
+
+    fn get_at(
+        &mut self,
+        user_addr: &UserAddress,
+        key: impl Key,
+        at: DateTime<Utc>,
+    ) -> Result<Option<UserStorageEntry>, Error> {
+        let key = key.to_string();
+        let revision = user_storage_history::table
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .filter(user_storage_history::created_at.le(at.timestamp_millis()))
+            .order(user_storage_history::id.desc())
+            .first::<RevisionRow>(&mut self.conn)
+            .optional()?
+            .map(RevisionRow::into_revision)
+            .transpose()?;
+
+        Ok(revision
+            .and_then(UserStorageRevision::entry)
+            .filter(|e| match e.expires_at {
+                Some(expires_at) => expires_at > at,
+                None => true,
+            }))
+    }
+
+    fn usage(&mut self, user_addr: &UserAddress) -> Result<UserStorageUsage, Error> {
+        user_storage_usage::table
+            .select((
+                user_storage_usage::entry_count,
+                user_storage_usage::total_bytes,
+            ))
+            .filter(user_storage_usage::user_addr.eq(user_addr))
+            .first(&mut self.conn)
+            .optional()
+            .map(Option::unwrap_or_default)
+            .map_err(Error::from)
+    }
+
+    fn list_users(
+        &mut self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<(UserAddress, UserStorageUsage)>, Error> {
+        let mut query = user_storage_usage::table
+            .select((
+                user_storage_usage::user_addr,
+                (
+                    user_storage_usage::entry_count,
+                    user_storage_usage::total_bytes,
+                ),
+            ))
+            .filter(user_storage_usage::entry_count.gt(0))
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(user_storage_usage::user_addr.gt(after));
+        }
+        query
+            .order(user_storage_usage::user_addr.asc())
+            .limit(limit)
+            .load(&mut self.conn)
+            .map_err(Error::from)
+    }
+
+    fn delete_expired(&mut self, limit: i64) -> Result<usize, Error> {
+        diesel::sql_query(
+            "DELETE FROM user_storage WHERE rowid IN (
+                SELECT rowid FROM user_storage WHERE expires_at <= ? LIMIT ?
+            )",
+        )
+        .bind::<BigInt, _>(now_millis())
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .map_err(Error::from)
+    }
+
+    fn publish(&mut self, user_addr: &UserAddress, changes: &[Change]) -> Result<(), Error> {
+        let created_at = now_millis();
+        let changes = changes
+            .iter()
+            .map(|c| {
+                let change = NewUserStorageChange::from((user_addr.clone(), c.clone()));
+                NewChangeRow {
+                    user_addr: change.user_addr,
+                    key: change.key,
+                    entry: change.entry.as_ref().map(Value::to_string),
+                    version: change.version,
+                    created_at,
+                }
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(user_storage_changes::table)
+            .values(&changes)
+            .execute(&mut self.conn)?;
+
+        self.notifications
+            .push(Notification::Changed(user_addr.clone()));
+        Ok(())
+    }
+
+    fn changes_since(
+        &mut self,
+        user_addr: &UserAddress,
+        prefix: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<UserStorageChange>, Error> {
+        user_storage_changes::table
+            .filter(user_storage_changes::user_addr.eq(user_addr))
+            .filter(user_storage_changes::id.gt(after_id))
+            .filter(
+                user_storage_changes::key
+                    .like(like_prefix_pattern(prefix))
+                    .escape('\\'),
+            )
+            .order(user_storage_changes::id.asc())
+            .limit(limit)
+            .load::<ChangeRow>(&mut self.conn)?
+            .into_iter()
+            .map(ChangeRow::into_change)
+            .collect()
+    }
+
+    fn last_change_id(&mut self, user_addr: &UserAddress) -> Result<i64, Error> {
+        user_storage_changes::table
+            .select(dsl::max(user_storage_changes::id))
+            .filter(user_storage_changes::user_addr.eq(user_addr))
+            .first::<Option<i64>>(&mut self.conn)
+            .map(Option::unwrap_or_default)
+            .map_err(Error::from)
+    }
+
+    fn delete_changes_before(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        diesel::sql_query(
+            "DELETE FROM user_storage_changes WHERE id IN (
+                SELECT id FROM user_storage_changes WHERE created_at < ? LIMIT ?
+            )",
+        )
+        .bind::<BigInt, _>(before.timestamp_millis())
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .map_err(Error::from)
+    }
+}
+
+impl SqliteStorage {
+    fn notify(&mut self, changes: &ChangeFeed) {
+        for notification in self.notifications.drain(..) {
+            match notification {
+                Notification::Changed(user_addr) => changes.notify(user_addr),
+                Notification::Erased(user_addr) => changes.erased(user_addr),
+                Notification::Reset => changes.reset(),
+            }
+        }
+    }
+
+    /// The version of the entry, expired or not.
+    fn version(&mut self, user_addr: &str, key: &str) -> Result<Option<i64>, Error> {
+        user_storage::table
+            .select(user_storage::version)
+            .filter(user_storage::user_addr.eq(user_addr))
+            .filter(user_storage::key.eq(key))
+            .first(&mut self.conn)
+            .optional()
+            .map_err(Error::from)
+    }
+
+    /// The version of a new entry follows the last recorded one of the key,
+    /// or the version floor of the user if higher, so that a deleted or erased
+    /// and re-created key never repeats its versions.
+    fn next_version(&mut self, user_addr: &str, key: &str) -> Result<i64, Error> {
+        let last_version = user_storage_history::table
+            .select(dsl::max(user_storage_history::version))
+            .filter(user_storage_history::user_addr.eq(user_addr))
+            .filter(user_storage_history::key.eq(key))
+            .first::<Option<i64>>(&mut self.conn)?;
+        let version_floor = user_storage_usage::table
+            .select(user_storage_usage::version_floor)
+            .filter(user_storage_usage::user_addr.eq(user_addr))
+            .first::<i64>(&mut self.conn)
+            .optional()?;
+        Ok(last_version.max(version_floor).unwrap_or_default() + 1)
+    }
+
+    /// Same as `ON CONFLICT DO UPDATE` of the Postgres repo,
+    /// the version of an existing entry is incremented.
+    fn upsert(&mut self, entry: &UserStorageEntry) -> Result<i64, Error> {
+        let row = EntryRow::from_entry(entry);
+        let version = match self.version(&entry.user_addr, &entry.key)? {
+            Some(old_version) => {
+                diesel::update(
+                    user_storage::table
+                        .filter(user_storage::user_addr.eq(&row.user_addr))
+                        .filter(user_storage::key.eq(&row.key)),
+                )
+                .set((
+                    user_storage::entry_type.eq(&row.entry_type),
+                    user_storage::entry_value_binary.eq(&row.entry_value_binary),
+                    user_storage::entry_value_boolean.eq(row.entry_value_boolean),
+                    user_storage::entry_value_integer.eq(row.entry_value_integer),
+                    user_storage::entry_value_json.eq(&row.entry_value_json),
+                    user_storage::entry_value_string.eq(&row.entry_value_string),
+                    user_storage::version.eq(old_version + 1),
+                    user_storage::expires_at.eq(row.expires_at),
+                ))
+                .execute(&mut self.conn)?;
+                old_version + 1
+            }
+            None => {
+                let row = EntryRow {
+                    version: self.next_version(&entry.user_addr, &entry.key)?,
+                    ..row
+                };
+                diesel::insert_into(user_storage::table)
+                    .values(&row)
+                    .execute(&mut self.conn)?;
+                row.version
+            }
+        };
+
+        self.record_history(NewUserStorageRevision {
+            version,
+            ..NewUserStorageRevision::from(entry)
+        })?;
+        Ok(version)
+    }
+
+    fn delete_if_expired(&mut self, user_addr: &str, key: &str) -> Result<(), Error> {
+        diesel::delete(
+            user_storage::table
+                .filter(user_storage::user_addr.eq(user_addr))
+                .filter(user_storage::key.eq(key))
+                .filter(user_storage::expires_at.le(now_millis())),
+        )
+        .execute(&mut self.conn)?;
+        Ok(())
+    }
+
+    fn record_history(&mut self, revision: NewUserStorageRevision) -> Result<(), Error> {
+        let row = NewRevisionRow {
+            user_addr: revision.user_addr,
+            key: revision.key,
+            entry_type: revision.entry_type,
+            entry_value_binary: revision.entry_value_binary,
+            entry_value_boolean: revision.entry_value_boolean,
+            entry_value_integer: revision.entry_value_integer,
+            entry_value_json: revision.entry_value_json.as_ref().map(Value::to_string),
+            entry_value_string: revision.entry_value_string,
+            version: revision.version,
+            expires_at: revision.expires_at.map(|at| at.timestamp_millis()),
+            created_at: now_millis(),
+        };
+        diesel::insert_into(user_storage_history::table)
+            .values(&row)
+            .execute(&mut self.conn)?;
+        Ok(())
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = user_storage)]
+struct EntryRow {
+    key: String,
+    user_addr: String,
+    entry_type: String,
+    entry_value_binary: Option<String>,
+    entry_value_boolean: Option<bool>,
+    entry_value_integer: Option<i64>,
+    entry_value_json: Option<String>,
+    entry_value_string: Option<String>,
+    version: i64,
+    expires_at: Option<i64>,
+}
+
+impl EntryRow {
+    fn from_entry(entry: &UserStorageEntry) -> Self {
+        EntryRow {
+            key: entry.key.clone(),
+            user_addr: entry.user_addr.clone(),
+            entry_type: entry.entry_type.clone(),
+            entry_value_binary: entry.entry_value_binary.clone(),
+            entry_value_boolean: entry.entry_value_boolean,
+            entry_value_integer: entry.entry_value_integer,
+            entry_value_json: entry.entry_value_json.as_ref().map(Value::to_string),
+            entry_value_string: entry.entry_value_string.clone(),
+            version: entry.version,
+            expires_at: entry.expires_at.map(|at| at.timestamp_millis()),
+        }
+    }
+
+    fn into_entry(self) -> Result<UserStorageEntry, Error> {
+        Ok(UserStorageEntry {
+            key: self.key,
+            user_addr: self.user_addr,
+            entry_type: self.entry_type,
+            entry_value_binary: self.entry_value_binary,
+            entry_value_boolean: self.entry_value_boolean,
+            entry_value_integer: self.entry_value_integer,
+            entry_value_json: parse_json(self.entry_value_json)?,
+            entry_value_string: self.entry_value_string,
+            version: self.version,
+            expires_at: self.expires_at.map(from_millis),
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_storage_history)]
+struct NewRevisionRow {
+    user_addr: String,
+    key: String,
+    entry_type: Option<String>,
+    entry_value_binary: Option<String>,
+    entry_value_boolean: Option<bool>,
+    entry_value_integer: Option<i64>,
+    entry_value_json: Option<String>,
+    entry_value_string: Option<String>,
+    version: i64,
+    expires_at: Option<i64>,
+    created_at: i64,
+}
+
+#[derive(Queryable)]
+struct RevisionRow {
+    id: i64,
+    user_addr: String,
+    key: String,
+    entry_type: Option<String>,
+    entry_value_binary: Option<String>,
+    entry_value_boolean: Option<bool>,
+    entry_value_integer: Option<i64>,
+    entry_value_json: Option<String>,
+    entry_value_string: Option<String>,
+    version: i64,
+    expires_at: Option<i64>,
+    created_at: i64,
+}
+
+impl RevisionRow {
+    fn into_revision(self) -> Result<UserStorageRevision, Error> {
+        Ok(UserStorageRevision {
+            id: self.id,
+            user_addr: self.user_addr,
+            key: self.key,
+            entry_type: self.entry_type,
+            entry_value_binary: self.entry_value_binary,
+            entry_value_boolean: self.entry_value_boolean,
+            entry_value_integer: self.entry_value_integer,
+            entry_value_json: parse_json(self.entry_value_json)?,
+            entry_value_string: self.entry_value_string,
+            version: self.version,
+            expires_at: self.expires_at.map(from_millis),
+            created_at: from_millis(self.created_at),
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_storage_changes)]
+struct NewChangeRow {
+    user_addr: String,
+    key: String,
+    entry: Option<String>,
+    version: i64,
+    created_at: i64,
+}
+
+#[derive(Queryable)]
+struct ChangeRow {
+    id: i64,
+    user_addr: String,
+    key: String,
+    entry: Option<String>,
+    version: i64,
+    created_at: i64,
+}
+
+impl ChangeRow {
+    fn into_change(self) -> Result<UserStorageChange, Error> {
+        Ok(UserStorageChange {
+            id: self.id,
+            user_addr: self.user_addr,
+            key: self.key,
+            entry: parse_json(self.entry)?,
+            version: self.version,
+            created_at: from_millis(self.created_at),
+        })
+    }
+}
+
+type NotExpired = dsl::Or<
+    dsl::IsNull<user_storage::expires_at>,
+    dsl::Gt<dsl::AssumeNotNull<user_storage::expires_at>, i64>,
+>;
+
+/// Filters out the entries which are past their expiration time.
+fn not_expired() -> NotExpired {
+    user_storage::expires_at
+        .is_null()
+        .or(user_storage::expires_at.assume_not_null().gt(now_millis()))
+}
+
+/// The keys sorted and deduplicated, for them to be split into `IN` lists.
+fn distinct_keys(keys: &[impl Key]) -> Vec<String> {
+    let mut keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+fn now_millis() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .expect("timestamp out of range")
+}
+
+fn parse_json(value: Option<String>) -> Result<Option<Value>, Error> {
+    value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .map_err(Error::from)
+}
@@ -10,5 +10,51 @@ diesel::table! {
         entry_value_integer -> Nullable<Int8>,
         entry_value_json -> Nullable<Jsonb>,
         entry_value_string -> Nullable<Text>,
+        version -> Int8,
+        expires_at -> Nullable<Timestamptz>,
     }
 }
+
+diesel::table! {
+    user_storage_history (id) {
+        id -> Int8,
+        user_addr -> Text,
+        key -> Text,
+        entry_type -> Nullable<Text>,
+        entry_value_binary -> Nullable<Text>,
+        entry_value_boolean -> Nullable<Bool>,
+        entry_value_integer -> Nullable<Int8>,
+        entry_value_json -> Nullable<Jsonb>,
+        entry_value_string -> Nullable<Text>,
+        version -> Int8,
+        expires_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_storage_changes (id) {
+        id -> Int8,
+        user_addr -> Text,
+        key -> Text,
+        entry -> Nullable<Jsonb>,
+        version -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_storage_usage (user_addr) {
+        user_addr -> Text,
+        entry_count -> Int8,
+        total_bytes -> Int8,
+        version_floor -> Int8,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    user_storage,
+    user_storage_changes,
+    user_storage_history,
+    user_storage_usage,
+);
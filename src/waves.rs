@@ -0,0 +1,67 @@
+//! Waves addresses and Curve25519 signatures.
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha3::Keccak256;
+
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+const ADDRESS_VERSION: u8 = 1;
+const ADDRESS_HASH_LENGTH: usize = 20;
+const ADDRESS_CHECKSUM_LENGTH: usize = 4;
+
+/// Derives the base58 encoded address of the account with the given public key.
+pub fn address_from_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH], chain_id: u8) -> String {
+    let mut address = vec![ADDRESS_VERSION, chain_id];
+    address.extend_from_slice(&secure_hash(public_key)[..ADDRESS_HASH_LENGTH]);
+    let checksum = secure_hash(&address);
+    address.extend_from_slice(&checksum[..ADDRESS_CHECKSUM_LENGTH]);
+    bs58::encode(address).into_string()
+}
+
+/// Checks that the address is a well-formed base58 encoded address of the given chain
+/// with a valid checksum.
+pub fn is_valid_address(address: &str, chain_id: u8) -> bool {
+    let address = match bs58::decode(address).into_vec() {
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    if address.len() != 2 + ADDRESS_HASH_LENGTH + ADDRESS_CHECKSUM_LENGTH
+        || address[0] != ADDRESS_VERSION
+        || address[1] != chain_id
+    {
+        return false;
+    }
+
+    let (body, checksum) = address.split_at(address.len() - ADDRESS_CHECKSUM_LENGTH);
+    secure_hash(body)[..ADDRESS_CHECKSUM_LENGTH] == *checksum
+}
+
+/// Verifies a signature the way Waves nodes do: the Curve25519 public key is converted
+/// to the Ed25519 one using the sign bit carried in the last byte of the signature.
+pub fn verify_signature(
+    public_key: &[u8; PUBLIC_KEY_LENGTH],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_LENGTH],
+) -> bool {
+    let mut signature = *signature;
+    let sign_bit = signature[SIGNATURE_LENGTH - 1] >> 7;
+    signature[SIGNATURE_LENGTH - 1] &= 0x7f;
+
+    let public_key = match MontgomeryPoint(*public_key).to_edwards(sign_bit) {
+        Some(point) => point.compress().to_bytes(),
+        None => return false,
+    };
+    match VerifyingKey::from_bytes(&public_key) {
+        Ok(key) => key
+            .verify(message, &Signature::from_bytes(&signature))
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn secure_hash(data: &[u8]) -> Vec<u8> {
+    Keccak256::digest(Blake2b::<U32>::digest(data)).to_vec()
+}
@@ -0,0 +1,7 @@
+//! The API suite run against the memory repo.
+
+mod common;
+
+use common::memory_storage as storage;
+
+include!("suites/api.rs");
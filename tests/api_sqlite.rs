@@ -0,0 +1,8 @@
+//! The API suite run against a SQLite database.
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use common::sqlite_storage as storage;
+
+include!("suites/api.rs");
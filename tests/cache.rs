@@ -0,0 +1,382 @@
+mod common;
+
+use common::{body, request, routes, string, user_addr};
+use deadpool_diesel::{PoolError, TimeoutType};
+use lib::changes::ChangeFeed;
+use lib::config::api::RateLimit;
+use lib::config::cache;
+use lib::error::Error;
+use lib::repo::cache::{CachedRepo, CACHE_HITS};
+use lib::repo::memory::{self, MemRepo, MemStorage};
+use lib::repo::{self, Repo, RepoOperations};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::filters::BoxedFilter;
+use warp::http::{header::WARNING, StatusCode};
+use warp::Reply;
+
+fn api<R: Repo>(repo: Arc<R>, changes: ChangeFeed) -> BoxedFilter<(impl Reply,)> {
+    routes(repo, changes, RateLimit::default())
+}
+
+fn cached<R, O>(repo: R, changes: &ChangeFeed) -> Arc<CachedRepo<R>>
+where
+    R: for<'a> Repo<Operations<'a> = O>,
+    O: RepoOperations + 'static,
+{
+    cached_with(repo, changes, 100, Duration::from_secs(60))
+}
+
+fn cached_with<R, O>(
+    repo: R,
+    changes: &ChangeFeed,
+    capacity: usize,
+    ttl: Duration,
+) -> Arc<CachedRepo<R>>
+where
+    R: for<'a> Repo<Operations<'a> = O>,
+    O: RepoOperations + 'static,
+{
+    let config = cache::Config {
+        capacity,
+        ttl,
+        // the storage is tried again right away, not to wait for it in the tests
+        stale_retry: Duration::ZERO,
+    };
+    Arc::new(repo::cache::new(repo, &config, changes))
+}
+
+/// Memory repo which can be made unavailable, as Postgres is during an incident,
+/// or busy, as the connection pool is under load.
+struct FlakyRepo {
+    inner: Arc<MemRepo>,
+    is_down: Arc<AtomicBool>,
+    is_busy: Arc<AtomicBool>,
+}
+
+impl FlakyRepo {
+    fn new(inner: Arc<MemRepo>) -> Self {
+        FlakyRepo {
+            inner,
+            is_down: Arc::new(AtomicBool::new(false)),
+            is_busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.is_down.load(Ordering::SeqCst) {
+            return Err(Error::StorageUnavailable("down".to_string()));
+        }
+        if self.is_busy.load(Ordering::SeqCst) {
+            return Err(Error::PoolError(PoolError::Timeout(TimeoutType::Wait)));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Repo for FlakyRepo {
+    type Operations<'a> = MemStorage;
+
+    async fn interact<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        self.check()?;
+        self.inner.interact(f).await
+    }
+
+    async fn transaction<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: for<'a> FnOnce(&mut Self::Operations<'a>) -> Result<R, Error>,
+        F: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+    {
+        self.check()?;
+        self.inner.transaction(f).await
+    }
+}
+
+/// The API of another replica sharing the storage, whose writes the cache learns of
+/// from the notifications only.
+fn other_replica(storage: Arc<MemRepo>) -> BoxedFilter<(impl Reply,)> {
+    api(storage, ChangeFeed::new())
+}
+
+/// A storage whose notifications don't reach the cache by themselves.
+fn shared_storage() -> Arc<MemRepo> {
+    Arc::new(memory::new(ChangeFeed::new()))
+}
+
+async fn put(api: &BoxedFilter<(impl Reply + 'static,)>, key: &str, value: &str) {
+    let response = request("PUT", &format!("/storage/{key}"))
+        .json(&string(value))
+        .reply(api)
+        .await;
+    assert!(response.status().is_success());
+}
+
+async fn get(api: &BoxedFilter<(impl Reply + 'static,)>, key: &str) -> Value {
+    body(&request("GET", &format!("/storage/{key}")).reply(api).await)
+}
+
+/// Notifications are handled by a task of their own, so they take effect a bit later.
+async fn assert_eventually_read(
+    api: &BoxedFilter<(impl Reply + 'static,)>,
+    key: &str,
+    expected: Value,
+) {
+    for _ in 0..100 {
+        if get(api, key).await == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("cached entry has not been invalidated");
+}
+
+#[tokio::test]
+async fn writes_invalidate_cached_entries() {
+    let changes = ChangeFeed::new();
+    let api = api(cached(memory::new(changes.clone()), &changes), changes);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("dark"));
+
+    let hits = CACHE_HITS.get();
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("dark"));
+    assert!(CACHE_HITS.get() > hits);
+
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "theme", "entry": string("light") },
+            { "key": "lang", "entry": string("en") },
+        ]}))
+        .reply(&api)
+        .await;
+    let response = request("POST", "/storage")
+        .json(&json!({ "keys": ["theme", "lang"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [string("light"), string("en")] })
+    );
+
+    request("DELETE", "/storage/theme").reply(&api).await;
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn failed_transaction_keeps_cached_entries_valid() {
+    let (changes, storage) = (ChangeFeed::new(), shared_storage());
+    let api = api(cached(FlakyRepo::new(storage.clone()), &changes), changes);
+    let other_replica = other_replica(storage);
+
+    put(&api, "theme", "dark").await;
+    get(&api, "theme").await;
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-Match", "\"5\"")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    put(&other_replica, "theme", "light").await;
+    let hits = CACHE_HITS.get();
+    assert_eq!(get(&api, "theme").await, string("dark"));
+    assert!(CACHE_HITS.get() > hits);
+}
+
+#[tokio::test]
+async fn notifications_of_other_replicas_invalidate_cached_entries() {
+    let (changes, storage) = (ChangeFeed::new(), shared_storage());
+    let api = api(
+        cached(FlakyRepo::new(storage.clone()), &changes),
+        changes.clone(),
+    );
+    let other_replica = other_replica(storage);
+
+    put(&api, "theme", "dark").await;
+    get(&api, "theme").await;
+    put(&other_replica, "theme", "light").await;
+    assert_eq!(get(&api, "theme").await, string("dark"));
+
+    changes.notify(user_addr());
+    assert_eventually_read(&api, "theme", string("light")).await;
+}
+
+#[tokio::test]
+async fn feed_reset_drops_all_cached_entries() {
+    let (changes, storage) = (ChangeFeed::new(), shared_storage());
+    let api = api(
+        cached(FlakyRepo::new(storage.clone()), &changes),
+        changes.clone(),
+    );
+    let other_replica = other_replica(storage);
+
+    put(&api, "theme", "dark").await;
+    get(&api, "theme").await;
+    put(&other_replica, "theme", "light").await;
+    assert_eq!(get(&api, "theme").await, string("dark"));
+
+    changes.reset();
+    assert_eventually_read(&api, "theme", string("light")).await;
+}
+
+#[tokio::test]
+async fn least_recently_used_entries_are_evicted_beyond_capacity() {
+    let (changes, storage) = (ChangeFeed::new(), shared_storage());
+    let repo = cached_with(
+        FlakyRepo::new(storage.clone()),
+        &changes,
+        2,
+        Duration::from_secs(60),
+    );
+    let api = api(repo, changes);
+    let other_replica = other_replica(storage);
+
+    for key in ["a", "b", "c"] {
+        put(&other_replica, key, "old").await;
+    }
+    get(&api, "a").await;
+    get(&api, "b").await;
+    get(&api, "a").await;
+    get(&api, "c").await;
+    for key in ["a", "b", "c"] {
+        put(&other_replica, key, "new").await;
+    }
+
+    assert_eq!(get(&api, "a").await, string("old"));
+    assert_eq!(get(&api, "c").await, string("old"));
+    assert_eq!(get(&api, "b").await, string("new"));
+}
+
+#[tokio::test]
+async fn cached_entries_expire_after_ttl() {
+    let (changes, storage) = (ChangeFeed::new(), shared_storage());
+    let repo = cached_with(
+        FlakyRepo::new(storage.clone()),
+        &changes,
+        100,
+        Duration::from_millis(100),
+    );
+    let api = api(repo, changes);
+    let other_replica = other_replica(storage);
+
+    put(&other_replica, "theme", "dark").await;
+    get(&api, "theme").await;
+    put(&other_replica, "theme", "light").await;
+    assert_eq!(get(&api, "theme").await, string("dark"));
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(get(&api, "theme").await, string("light"));
+}
+
+#[tokio::test]
+async fn cached_entries_are_served_stale_while_storage_is_down() {
+    let changes = ChangeFeed::new();
+    let repo = FlakyRepo::new(Arc::new(memory::new(changes.clone())));
+    let is_down = repo.is_down.clone();
+    let api = api(cached(repo, &changes), changes);
+
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert!(response.headers().get("X-Stale").is_none());
+
+    is_down.store(true, Ordering::SeqCst);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["X-Stale"], "true");
+    assert_eq!(response.headers()[WARNING], "110 - \"Response is Stale\"");
+    assert_eq!(body(&response), string("dark"));
+
+    let response = request("GET", "/storage/lang").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let response = request("PUT", "/storage/theme")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        body(&response),
+        json!({ "errors": [{
+            "code": 950503,
+            "message": "Service Unavailable",
+            "details": { "reason": "storage is unavailable" },
+        }]})
+    );
+
+    is_down.store(false, Ordering::SeqCst);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("X-Stale").is_none());
+}
+
+#[tokio::test]
+async fn cached_entries_are_not_served_stale_while_pool_is_busy() {
+    let changes = ChangeFeed::new();
+    let repo = FlakyRepo::new(Arc::new(memory::new(changes.clone())));
+    let is_busy = repo.is_busy.clone();
+    let api = api(cached(repo, &changes), changes);
+
+    put(&api, "theme", "dark").await;
+    get(&api, "theme").await;
+
+    is_busy.store(true, Ordering::SeqCst);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    is_busy.store(false, Ordering::SeqCst);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("X-Stale").is_none());
+}
+
+#[tokio::test]
+async fn only_interactions_reading_the_cache_alone_are_stale() {
+    let changes = ChangeFeed::new();
+    let repo = FlakyRepo::new(Arc::new(memory::new(changes.clone())));
+    let is_down = repo.is_down.clone();
+    let repo = cached(repo, &changes);
+    let api = api(repo.clone(), changes);
+
+    put(&api, "theme", "dark").await;
+    get(&api, "theme").await;
+    let read = || repo.interact_tracking_stale(|ops| ops.get(&user_addr(), "theme"));
+
+    let (entry, is_stale) = read().await.unwrap();
+    assert!(entry.is_some());
+    assert!(!is_stale);
+
+    is_down.store(true, Ordering::SeqCst);
+
+    let (entry, is_stale) = read().await.unwrap();
+    assert!(entry.is_some());
+    assert!(is_stale);
+
+    let ((), is_stale) = repo.interact_tracking_stale(|_| Ok(())).await.unwrap();
+    assert!(!is_stale);
+}
@@ -0,0 +1,130 @@
+//! Helpers shared by the API test suites, each of which uses some of them.
+#![allow(dead_code)]
+
+use lib::api::{self, rate_limit::InMemoryBuckets};
+use lib::changes::ChangeFeed;
+use lib::config::api::{Auth, Config, Quota, RateLimit};
+use lib::repo::memory::{self, MemRepo};
+use lib::repo::Repo;
+use lib::waves;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::hyper::body::Bytes;
+use warp::test::RequestBuilder;
+use warp::Reply;
+#[cfg(feature = "sqlite")]
+use {
+    diesel::{Connection, SqliteConnection},
+    diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness},
+    lib::repo::sqlite::{self, SqliteRepo},
+    std::process,
+    std::sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub const CHAIN_ID: u8 = b'W';
+pub const MAX_RAW_BODY_SIZE: u64 = 1024;
+
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+pub fn memory_storage(changes: ChangeFeed) -> Arc<MemRepo> {
+    Arc::new(memory::new(changes))
+}
+
+/// A repo on a temporary SQLite database of its own.
+#[cfg(feature = "sqlite")]
+pub fn sqlite_storage(changes: ChangeFeed) -> Arc<SqliteRepo> {
+    let path = sqlite_database();
+    Arc::new(sqlite::new(&path, changes).expect("failed to open the database"))
+}
+
+/// Creates a migrated SQLite database of its own in the temporary directory,
+/// returning its path.
+#[cfg(feature = "sqlite")]
+pub fn sqlite_database() -> String {
+    static DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "user-storage-test-{}-{}.db",
+        process::id(),
+        DATABASES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let path = path
+        .to_str()
+        .expect("temporary path is not utf-8")
+        .to_string();
+    SqliteConnection::establish(&path)
+        .expect("failed to create the database")
+        .run_pending_migrations(SQLITE_MIGRATIONS)
+        .expect("failed to migrate the database");
+    path
+}
+
+/// The configuration the suites are run with unless a test needs another one.
+pub fn config() -> Config {
+    Config {
+        port: 0,
+        metrics_port: 0,
+        chain_id: CHAIN_ID,
+        auth: Auth::Header,
+        quota: Quota::default(),
+        rate_limit: RateLimit::default(),
+        max_raw_body_size: MAX_RAW_BODY_SIZE,
+        admin: None,
+    }
+}
+
+pub fn routes<R: Repo>(
+    repo: Arc<R>,
+    changes: ChangeFeed,
+    rate_limit: RateLimit,
+) -> BoxedFilter<(impl Reply,)> {
+    routes_with(
+        repo,
+        changes,
+        &Config {
+            rate_limit,
+            ..config()
+        },
+    )
+}
+
+pub fn routes_with<R: Repo>(
+    repo: Arc<R>,
+    changes: ChangeFeed,
+    config: &Config,
+) -> BoxedFilter<(impl Reply,)> {
+    api::routes(config, repo, changes, Arc::new(InMemoryBuckets::default()))
+}
+
+pub fn request(method: &str, path: &str) -> RequestBuilder {
+    warp::test::request()
+        .method(method)
+        .path(path)
+        .header("X-User-Address", user_addr())
+}
+
+pub fn user_addr() -> String {
+    waves::address_from_public_key(&[1; waves::PUBLIC_KEY_LENGTH], CHAIN_ID)
+}
+
+pub fn other_user_addr() -> String {
+    waves::address_from_public_key(&[2; waves::PUBLIC_KEY_LENGTH], CHAIN_ID)
+}
+
+pub fn body(response: &warp::http::Response<Bytes>) -> Value {
+    serde_json::from_slice(response.body()).expect("response body is not json")
+}
+
+pub fn string(value: &str) -> Value {
+    json!({ "type": "string", "value": value })
+}
+
+pub fn integer(value: i64) -> Value {
+    json!({ "type": "integer", "value": value })
+}
+
+pub fn json_entry(value: Value) -> Value {
+    json!({ "type": "json", "value": value })
+}
@@ -0,0 +1,93 @@
+//! Behaviour of the memory repo beyond what the API suite covers.
+
+mod common;
+
+use common::{string, user_addr};
+use lib::changes::ChangeFeed;
+use lib::error::Error;
+use lib::models::{dto, UserStorageEntry};
+use lib::repo::memory::{self, MemRepo};
+use lib::repo::{Repo, RepoOperations};
+use serde_json::Value;
+use std::sync::Arc;
+
+fn entry(key: &str, value: Value) -> UserStorageEntry {
+    let value: dto::Entry = serde_json::from_value(value).expect("invalid entry");
+    UserStorageEntry::from((user_addr(), key.to_string(), value))
+}
+
+async fn get(repo: &MemRepo, key: &'static str) -> Option<UserStorageEntry> {
+    repo.interact(move |ops| ops.get(&user_addr(), key))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn failed_transaction_is_undone() {
+    let repo = memory::new(ChangeFeed::new());
+    repo.transaction(|ops| ops.set(&entry("theme", string("dark"))))
+        .await
+        .unwrap();
+
+    let result = repo
+        .transaction(|ops| {
+            ops.set(&entry("theme", string("light")))?;
+            ops.set(&entry("lang", string("en")))?;
+            ops.publish(
+                &user_addr(),
+                &[dto::Change::deleted("theme".to_string(), 2)],
+            )?;
+            ops.delete_all(&user_addr())?;
+            Err::<(), _>(Error::GeneralError("failed".to_string()))
+        })
+        .await;
+    assert!(result.is_err());
+
+    let theme = get(&repo, "theme").await.unwrap();
+    assert_eq!(theme.entry_value_string.as_deref(), Some("dark"));
+    assert_eq!(theme.version, 1);
+    assert!(get(&repo, "lang").await.is_none());
+    let (history, changes) = repo
+        .interact(|ops| {
+            let history = ops.history(&user_addr(), "theme", None, 10)?;
+            let changes = ops.changes_since(&user_addr(), "", 0, 10)?;
+            Ok((history, changes))
+        })
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+    assert!(changes.is_empty());
+
+    // the undone writes leave no gaps in the versions
+    let version = repo
+        .transaction(|ops| ops.set(&entry("theme", string("light"))))
+        .await
+        .unwrap();
+    assert_eq!(version, 2);
+}
+
+#[tokio::test]
+async fn panicked_transaction_is_undone_and_later_ones_committed() {
+    let repo = Arc::new(memory::new(ChangeFeed::new()));
+
+    let panicked = tokio::spawn({
+        let repo = repo.clone();
+        async move {
+            repo.transaction(|ops| {
+                ops.set(&entry("theme", string("dark")))?;
+                panic!("handler panicked");
+                #[allow(unreachable_code)]
+                Ok(())
+            })
+            .await
+        }
+    })
+    .await;
+    assert!(panicked.is_err());
+
+    repo.transaction(|ops| ops.set(&entry("lang", string("en"))))
+        .await
+        .unwrap();
+    assert!(get(&repo, "theme").await.is_none());
+    assert!(get(&repo, "lang").await.is_some());
+}
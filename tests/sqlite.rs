@@ -0,0 +1,189 @@
+//! Behaviour of the SQLite repo beyond what the API suite covers, which is run against it too
+//! with the `sqlite` feature.
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use chrono::{Duration, Utc};
+use common::{sqlite_database, string, user_addr};
+use lib::changes::ChangeFeed;
+use lib::error::Error;
+use lib::models::{dto, UserStorageEntry};
+use lib::repo::sqlite::{self, SqliteRepo};
+use lib::repo::{Repo, RepoOperations};
+use serde_json::Value;
+use std::sync::Arc;
+
+fn repo(path: &str) -> SqliteRepo {
+    sqlite::new(path, ChangeFeed::new()).expect("failed to open the database")
+}
+
+fn entry(key: &str, value: Value) -> UserStorageEntry {
+    let value: dto::Entry = serde_json::from_value(value).expect("invalid entry");
+    UserStorageEntry::from((user_addr(), key.to_string(), value))
+}
+
+async fn get(repo: &SqliteRepo, key: &'static str) -> Option<UserStorageEntry> {
+    repo.interact(move |ops| ops.get(&user_addr(), key))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn failed_transaction_is_rolled_back() {
+    let path = sqlite_database();
+    let repo = repo(&path);
+
+    let result = repo
+        .transaction(|ops| {
+            ops.set(&entry("theme", string("dark")))?;
+            Err::<(), _>(Error::GeneralError("failed".to_string()))
+        })
+        .await;
+    assert!(result.is_err());
+    assert!(get(&repo, "theme").await.is_none());
+}
+
+#[tokio::test]
+async fn panicked_transaction_is_rolled_back_and_later_ones_committed() {
+    let path = sqlite_database();
+    let repo = Arc::new(repo(&path));
+
+    let panicked = tokio::spawn({
+        let repo = repo.clone();
+        async move {
+            repo.transaction(|ops| {
+                ops.set(&entry("theme", string("dark")))?;
+                panic!("handler panicked");
+                #[allow(unreachable_code)]
+                Ok(())
+            })
+            .await
+        }
+    })
+    .await;
+    assert!(panicked.is_err());
+
+    repo.transaction(|ops| ops.set(&entry("lang", string("en"))))
+        .await
+        .unwrap();
+
+    // read over a connection of its own, seeing the committed data only
+    let other = self::repo(&path);
+    assert!(get(&other, "theme").await.is_none());
+    assert!(get(&other, "lang").await.is_some());
+}
+
+#[tokio::test]
+async fn expired_entries_are_hidden_then_deleted() {
+    let path = sqlite_database();
+    let repo = repo(&path);
+
+    let mut expired = entry("session", string("token"));
+    expired.expires_at = Some(Utc::now() - Duration::seconds(1));
+    let mut alive = entry("theme", string("dark"));
+    alive.expires_at = Some(Utc::now() + Duration::hours(1));
+    repo.transaction(move |ops| ops.mset(&[expired, alive]))
+        .await
+        .unwrap();
+
+    assert!(get(&repo, "session").await.is_none());
+    assert!(get(&repo, "theme").await.is_some());
+
+    let deleted = repo.interact(|ops| ops.delete_expired(100)).await.unwrap();
+    assert_eq!(deleted, 1);
+    let deleted = repo.interact(|ops| ops.delete_expired(100)).await.unwrap();
+    assert_eq!(deleted, 0);
+    assert!(get(&repo, "theme").await.is_some());
+}
+
+#[tokio::test]
+async fn usage_is_kept_by_triggers() {
+    let path = sqlite_database();
+    let repo = repo(&path);
+    let usage = || repo.interact(|ops| ops.usage(&user_addr()));
+
+    assert_eq!(usage().await.unwrap().entry_count, 0);
+
+    repo.transaction(|ops| {
+        ops.mset(&[entry("theme", string("dark")), entry("lang", string("en"))])
+    })
+    .await
+    .unwrap();
+    let both = usage().await.unwrap();
+    assert_eq!(both.entry_count, 2);
+    assert!(both.total_bytes > 0);
+
+    repo.transaction(|ops| ops.set(&entry("theme", string("a much darker one"))))
+        .await
+        .unwrap();
+    let grown = usage().await.unwrap();
+    assert_eq!(grown.entry_count, 2);
+    assert!(grown.total_bytes > both.total_bytes);
+
+    repo.transaction(|ops| ops.mdel(&user_addr(), &["theme"]))
+        .await
+        .unwrap();
+    let one = usage().await.unwrap();
+    assert_eq!(one.entry_count, 1);
+    assert!(one.total_bytes < grown.total_bytes);
+
+    repo.transaction(|ops| ops.delete_all(&user_addr()))
+        .await
+        .unwrap();
+    let none = usage().await.unwrap();
+    assert_eq!((none.entry_count, none.total_bytes), (0, 0));
+}
+
+#[tokio::test]
+async fn keys_are_read_and_deleted_in_chunks() {
+    let path = sqlite_database();
+    let repo = repo(&path);
+    // more keys than bound in a single statement, the page crossing the chunks of them
+    let keys = (0..1500).map(|i| format!("key{i:04}")).collect::<Vec<_>>();
+
+    let entries = keys
+        .iter()
+        .map(|key| entry(key, string("value")))
+        .collect::<Vec<_>>();
+    repo.transaction(move |ops| ops.mset(&entries))
+        .await
+        .unwrap();
+
+    let read = {
+        let keys = keys.clone();
+        repo.interact(move |ops| ops.mget(&user_addr(), &keys))
+            .await
+            .unwrap()
+    };
+    assert_eq!(read.len(), keys.len());
+
+    let page = {
+        let keys = keys.clone();
+        repo.interact(move |ops| ops.mget_page(&user_addr(), &keys, Some("key0400"), 200))
+            .await
+            .unwrap()
+    };
+    let page = page.iter().map(|e| e.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        page,
+        keys[401..601]
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+    );
+
+    let count = keys.len();
+    let deleted = repo
+        .transaction(move |ops| ops.mdel(&user_addr(), &keys))
+        .await
+        .unwrap();
+    assert_eq!(deleted.len(), count);
+    assert_eq!(
+        repo.interact(|ops| ops.usage(&user_addr()))
+            .await
+            .unwrap()
+            .entry_count,
+        0
+    );
+}
@@ -0,0 +1,1359 @@
+// The API suite, included by the test crates running it against each of the repos,
+// which define the `storage` it's run against.
+
+use chrono::Utc;
+use common::{
+    body, config, integer, json_entry, other_user_addr, request, routes, routes_with, string,
+    user_addr, CHAIN_ID,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signer, SigningKey};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use lib::changes::{ChangeFeed, Notification};
+use lib::config::api::{Auth, Budget, Config, JwtConfig, Quota, RateLimit};
+use lib::repo::{Repo, RepoOperations};
+use lib::waves;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use warp::filters::BoxedFilter;
+use warp::http::{
+    header::{CONTENT_TYPE, ETAG, RETRY_AFTER},
+    StatusCode,
+};
+use warp::test::WsClient;
+use warp::Reply;
+
+fn api() -> BoxedFilter<(impl Reply,)> {
+    rate_limited_api(RateLimit::default())
+}
+
+fn rate_limited_api(rate_limit: RateLimit) -> BoxedFilter<(impl Reply,)> {
+    let changes = ChangeFeed::new();
+    routes(storage(changes.clone()), changes, rate_limit)
+}
+
+#[tokio::test]
+async fn set_single_entry_creates_then_returns_old_entry() {
+    let api = api();
+
+    let created = request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    assert_eq!(created.status(), StatusCode::CREATED);
+    assert_eq!(created.headers()[ETAG], "\"1\"");
+    assert!(created.body().is_empty());
+
+    let updated = request("PUT", "/storage/theme")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(updated.status(), StatusCode::OK);
+    assert_eq!(updated.headers()[ETAG], "\"2\"");
+    assert_eq!(body(&updated), string("dark"));
+}
+
+#[tokio::test]
+async fn get_single_entry() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ETAG], "\"1\"");
+    assert_eq!(body(&response), string("dark"));
+}
+
+#[tokio::test]
+async fn missing_entry_is_not_found() {
+    let api = api();
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body(&response)["errors"][0]["code"], 950404);
+
+    let response = request("DELETE", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn delete_single_entry_returns_old_entry() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = request("DELETE", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), string("dark"));
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn entries_are_separated_by_user() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = warp::test::request()
+        .method("GET")
+        .path("/storage/theme")
+        .header("X-User-Address", other_user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn batch_get_via_query() {
+    let api = api();
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "a", "entry": integer(1) },
+            { "key": "b", "entry": string("two") },
+        ]}))
+        .reply(&api)
+        .await;
+
+    let response = request(
+        "GET",
+        "/storage?keys%5B0%5D=a&keys%5B1%5D=missing&keys%5B2%5D=b",
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [integer(1), null, string("two")] })
+    );
+}
+
+#[tokio::test]
+async fn batch_get_via_post() {
+    let api = api();
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "a", "entry": integer(1) },
+            { "key": "b", "entry": string("two") },
+        ]}))
+        .reply(&api)
+        .await;
+
+    let response = request("POST", "/storage")
+        .json(&json!({ "keys": ["b", "missing", "a"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [string("two"), null, integer(1)] })
+    );
+}
+
+async fn put_entries(api: &BoxedFilter<(impl Reply + 'static,)>, entries: Value) {
+    let response = request("PUT", "/storage")
+        .json(&json!({ "entries": entries }))
+        .reply(api)
+        .await;
+    assert!(response.status().is_success());
+}
+
+#[tokio::test]
+async fn batch_get_pages_follow_the_cursor() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "a", "entry": integer(1) },
+            { "key": "b", "entry": integer(2) },
+            { "key": "c", "entry": integer(3) },
+        ]),
+    )
+    .await;
+
+    let response = request(
+        "GET",
+        "/storage?keys%5B0%5D=c&keys%5B1%5D=missing&keys%5B2%5D=a&limit=1",
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = body(&response);
+    assert_eq!(page["keys"], json!(["a"]));
+    assert_eq!(page["entries"], json!([integer(1)]));
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+
+    let response = request("POST", &format!("/storage?limit=1&after={cursor}"))
+        .json(&json!({ "keys": ["c", "missing", "a"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(
+        body(&response),
+        json!({ "keys": ["c"], "entries": [integer(3)] })
+    );
+}
+
+#[tokio::test]
+async fn invalid_page_is_rejected_rather_than_read_whole() {
+    let api = api();
+    put_entries(&api, json!([{ "key": "a", "entry": integer(1) }])).await;
+
+    for (method, path, parameter) in [
+        ("GET", "/storage?keys%5B0%5D=a&limit=0", "limit"),
+        ("GET", "/storage?keys%5B0%5D=a&after=%21", "after"),
+        ("POST", "/storage?limit=5000", "limit"),
+    ] {
+        let response = request(method, path)
+            .json(&json!({ "keys": ["a"] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+        assert_eq!(
+            body(&response)["errors"][0]["details"]["parameter"],
+            parameter
+        );
+    }
+}
+
+#[tokio::test]
+async fn keys_are_listed_by_prefix_page_by_page() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "ui.theme", "entry": string("dark") },
+            { "key": "ui.lang", "entry": string("en") },
+            { "key": "ui.font", "entry": string("mono") },
+            { "key": "wallet", "entry": string("main") },
+        ]),
+    )
+    .await;
+
+    let response = request("GET", "/storage/keys?prefix=ui.&limit=2")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = body(&response);
+    assert_eq!(page["keys"], json!(["ui.font", "ui.lang"]));
+    assert!(page.get("entries").is_none());
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+
+    let response = request(
+        "GET",
+        &format!("/storage/keys?prefix=ui.&limit=2&with_entries=true&after={cursor}"),
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(
+        body(&response),
+        json!({ "keys": ["ui.theme"], "entries": [string("dark")] })
+    );
+
+    let response = request("GET", "/storage/keys").reply(&api).await;
+    assert_eq!(
+        body(&response)["keys"],
+        json!(["ui.font", "ui.lang", "ui.theme", "wallet"])
+    );
+}
+
+#[tokio::test]
+async fn batch_put_returns_old_entries_and_deletes_nulls() {
+    let api = api();
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "a", "entry": integer(1) },
+            { "key": "b", "entry": string("two") },
+        ]}))
+        .reply(&api)
+        .await;
+
+    let response = request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "a", "entry": integer(2) },
+            { "key": "b", "entry": null },
+            { "key": "c", "entry": { "type": "boolean", "value": true } },
+        ]}))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [integer(1), string("two"), null] })
+    );
+
+    let response = request("POST", "/storage")
+        .json(&json!({ "keys": ["a", "b", "c"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [
+            integer(2),
+            null,
+            { "type": "boolean", "value": true },
+        ]})
+    );
+}
+
+#[tokio::test]
+async fn batch_delete_returns_old_entries() {
+    let api = api();
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "a", "entry": integer(1) },
+            { "key": "b", "entry": string("two") },
+        ]}))
+        .reply(&api)
+        .await;
+
+    let response = request("DELETE", "/storage")
+        .json(&json!({ "keys": ["a", "missing"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), json!({ "entries": [integer(1), null] }));
+
+    let response = request("POST", "/storage")
+        .json(&json!({ "keys": ["a", "b"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(body(&response), json!({ "entries": [null, string("two")] }));
+}
+
+#[tokio::test]
+async fn validation_error_names_the_parameter() {
+    let api = api();
+
+    let response = request(
+        "PUT",
+        "/storage/theme?ttl_seconds=60&expires_at=2030-01-01T00:00:00Z",
+    )
+    .json(&string("dark"))
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let details = &body(&response)["errors"][0]["details"];
+    assert_eq!(details["parameter"], "theme");
+    assert_eq!(
+        details["reason"],
+        "ttl_seconds and expires_at are mutually exclusive"
+    );
+}
+
+#[tokio::test]
+async fn precondition_failed_error_body() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-Match", "\"5\"")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(
+        body(&response),
+        json!({ "errors": [{
+            "code": 950412,
+            "message": "Precondition Failed",
+            "details": { "key": "theme" },
+        }]})
+    );
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("dark"));
+}
+
+#[tokio::test]
+async fn recreated_entry_does_not_reuse_versions() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    request("DELETE", "/storage/theme").reply(&api).await;
+
+    let response = request("PUT", "/storage/theme")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()[ETAG], "\"3\"");
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-Match", "\"1\"")
+        .json(&string("blue"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+}
+
+#[tokio::test]
+async fn if_none_match_rejects_the_current_version() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-None-Match", "\"2\", \"1\"")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-None-Match", "\"2\"")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ETAG], "\"2\"");
+}
+
+#[tokio::test]
+async fn incr_out_of_integer_range_is_invalid() {
+    let api = api();
+    request("PUT", "/storage/counter")
+        .json(&integer(i64::MAX - 1))
+        .reply(&api)
+        .await;
+
+    let response = request("POST", "/storage/counter/incr")
+        .json(&json!({ "delta": 2 }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body(&response)["errors"][0]["details"]["parameter"],
+        "delta"
+    );
+
+    let response = request("POST", "/storage/counter/incr")
+        .json(&json!({ "delta": 2, "max": i64::MAX }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), integer(i64::MAX));
+}
+
+#[tokio::test]
+async fn rate_limit_rejected_request_takes_no_tokens() {
+    let api = rate_limited_api(RateLimit {
+        write: Some(Budget {
+            per_second: 0.001,
+            burst: 1,
+        }),
+        by_ip: true,
+        ..RateLimit::default()
+    });
+    let put = |user_addr: String, ip: &str| {
+        warp::test::request()
+            .method("PUT")
+            .path("/storage/theme")
+            .header("X-User-Address", user_addr)
+            .remote_addr(SocketAddr::new(ip.parse().unwrap(), 443))
+            .json(&string("dark"))
+    };
+
+    let response = put(user_addr(), "192.0.2.1").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = put(user_addr(), "192.0.2.2").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+
+    // the IP is out of tokens, and so the user's token is not taken either
+    let response = put(other_user_addr(), "192.0.2.1").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = put(other_user_addr(), "192.0.2.2").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn rate_limit_ignores_forwarded_for_entries_of_untrusted_proxies() {
+    let api = rate_limited_api(RateLimit {
+        read: Some(Budget {
+            per_second: 0.001,
+            burst: 1,
+        }),
+        by_ip: true,
+        trusted_proxies: 1,
+        ..RateLimit::default()
+    });
+    let get = |user_addr: String, forwarded_for: &str| {
+        warp::test::request()
+            .path("/storage/theme")
+            .header("X-User-Address", user_addr)
+            .header("X-Forwarded-For", forwarded_for)
+            .remote_addr("10.0.0.2:443".parse().unwrap())
+    };
+
+    let response = get(user_addr(), "198.51.100.1, 192.0.2.1")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = get(other_user_addr(), "198.51.100.2, 192.0.2.1")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn rate_limit_skips_unauthenticated_requests() {
+    let api = rate_limited_api(RateLimit {
+        read: Some(Budget {
+            per_second: 0.001,
+            burst: 1,
+        }),
+        ..RateLimit::default()
+    });
+
+    let response = warp::test::request()
+        .path("/storage/theme")
+        .header("X-User-Address", "not an address")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn ws_get(socket: &mut WsClient, request_id: &str) -> Value {
+    socket
+        .send_text(
+            json!({ "type": "get", "request_id": request_id, "keys": ["theme"] }).to_string(),
+        )
+        .await;
+    let message = socket.recv().await.expect("socket closed");
+    serde_json::from_str(message.to_str().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn websocket_requests_share_the_rate_limit() {
+    let api = rate_limited_api(RateLimit {
+        read: Some(Budget {
+            per_second: 0.001,
+            burst: 2,
+        }),
+        ..RateLimit::default()
+    });
+
+    // the upgrade request takes the first token
+    let mut socket = warp::test::ws()
+        .path("/storage/ws")
+        .header("X-User-Address", user_addr())
+        .handshake(api)
+        .await
+        .expect("handshake failed");
+    let response = ws_get(&mut socket, "1").await;
+    assert_eq!(response["type"], "entries");
+    let response = ws_get(&mut socket, "2").await;
+    assert_eq!(response["type"], "error");
+    assert_eq!(response["request_id"], "2");
+    assert_eq!(response["message"], "Too Many Requests");
+}
+
+#[tokio::test]
+async fn import_body_over_the_size_limit_is_rejected() {
+    let api = api();
+
+    let response = request("POST", "/storage/import")
+        .json(&json!({ "entries": [{ "key": "theme", "entry": string("dark") }] }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = request("POST", "/storage/import")
+        .json(&json!({ "entries": [{ "key": "theme", "entry": string(&"a".repeat(1024)) }] }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        body(&response),
+        json!({ "errors": [{
+            "code": 950413,
+            "message": "Payload Too Large",
+            "details": { "max_size": "1024" },
+        }]})
+    );
+}
+
+#[tokio::test]
+async fn erasure_notifies_without_recording_changes() {
+    let changes = ChangeFeed::new();
+    let repo = storage(changes.clone());
+    let api = routes(repo.clone(), changes.clone(), RateLimit::default());
+
+    request("PUT", "/storage")
+        .json(&json!({ "entries": [
+            { "key": "theme", "entry": string("dark") },
+            { "key": "lang", "entry": string("en") },
+        ]}))
+        .reply(&api)
+        .await;
+    let mut notifications = changes.subscribe();
+
+    let response = request(
+        "DELETE",
+        &format!("/storage?all=true&confirm={}", user_addr()),
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), json!({ "deleted": 2 }));
+    assert_eq!(
+        notifications.try_recv().unwrap(),
+        Notification::Erased(user_addr())
+    );
+
+    let published = repo
+        .interact(|ops| ops.changes_since(&user_addr(), "", 0, 10))
+        .await
+        .unwrap();
+    assert!(published.is_empty());
+}
+
+#[tokio::test]
+async fn versions_are_not_repeated_after_erasure() {
+    let api = api();
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    request("PUT", "/storage/theme")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+
+    request(
+        "DELETE",
+        &format!("/storage?all=true&confirm={}", user_addr()),
+    )
+    .reply(&api)
+    .await;
+
+    let response = request("PUT", "/storage/theme")
+        .json(&string("blue"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()[ETAG], "\"3\"");
+
+    let response = request("PUT", "/storage/theme")
+        .header("If-Match", "\"1\"")
+        .json(&string("green"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+}
+
+#[tokio::test]
+async fn erasure_with_wrong_confirmation_deletes_nothing() {
+    let api = api();
+    put_entries(&api, json!([{ "key": "theme", "entry": string("dark") }])).await;
+
+    let response = request(
+        "DELETE",
+        &format!("/storage?all=true&confirm={}", other_user_addr()),
+    )
+    .json(&json!({ "keys": ["theme"] }))
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn json_path_narrows_the_entry_down() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "profile", "entry": json_entry(json!({ "name": "Alice", "langs": ["en", "fr"] })) },
+            { "key": "theme", "entry": string("dark") },
+        ]),
+    )
+    .await;
+
+    let response = request("GET", "/storage/profile?path=$.langs%5B1%5D")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), json_entry(json!("fr")));
+
+    let response = request("GET", "/storage/profile?path=$.missing")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = request("POST", "/storage")
+        .json(&json!({ "keys": ["profile", "theme"], "paths": { "profile": "$.name" } }))
+        .reply(&api)
+        .await;
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [json_entry(json!("Alice")), string("dark")] })
+    );
+
+    // only the json entries have paths
+    let response = request("GET", "/storage/theme?path=$.name")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+fn patch(key: &str, content_type: &str, patch: Value) -> warp::test::RequestBuilder {
+    request("PATCH", &format!("/storage/{key}"))
+        .header(CONTENT_TYPE, content_type)
+        .body(patch.to_string())
+}
+
+#[tokio::test]
+async fn merge_patch_updates_the_document() {
+    let api = api();
+    request("PUT", "/storage/profile")
+        .json(&json_entry(
+            json!({ "name": "Alice", "lang": "en", "tags": ["a"] }),
+        ))
+        .reply(&api)
+        .await;
+
+    let response = patch(
+        "profile",
+        "application/merge-patch+json",
+        json!({ "lang": null, "tags": ["b"], "theme": "dark" }),
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ETAG], "\"2\"");
+    assert_eq!(
+        body(&response),
+        json_entry(json!({ "name": "Alice", "tags": ["b"], "theme": "dark" }))
+    );
+}
+
+#[tokio::test]
+async fn json_patch_applies_all_the_operations_or_none() {
+    let api = api();
+    request("PUT", "/storage/profile")
+        .json(&json_entry(json!({ "name": "Alice", "tags": ["a"] })))
+        .reply(&api)
+        .await;
+
+    let response = patch(
+        "profile",
+        "application/json-patch+json",
+        json!([
+            { "op": "add", "path": "/tags/-", "value": "b" },
+            { "op": "replace", "path": "/name", "value": "Bob" },
+        ]),
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(&response),
+        json_entry(json!({ "name": "Bob", "tags": ["a", "b"] }))
+    );
+
+    let response = patch(
+        "profile",
+        "application/json-patch+json",
+        json!([
+            { "op": "add", "path": "/theme", "value": "dark" },
+            { "op": "test", "path": "/name", "value": "Alice" },
+        ]),
+    )
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = request("GET", "/storage/profile").reply(&api).await;
+    assert_eq!(response.headers()[ETAG], "\"2\"");
+    assert_eq!(
+        body(&response),
+        json_entry(json!({ "name": "Bob", "tags": ["a", "b"] }))
+    );
+}
+
+#[tokio::test]
+async fn patch_is_conditional_and_of_json_entries_only() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "profile", "entry": json_entry(json!({ "name": "Alice" })) },
+            { "key": "theme", "entry": string("dark") },
+        ]),
+    )
+    .await;
+    let rename = json!({ "name": "Bob" });
+
+    let response = patch("profile", "application/merge-patch+json", rename.clone())
+        .header("If-Match", "\"2\"")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = patch("theme", "application/merge-patch+json", rename.clone())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = patch("missing", "application/merge-patch+json", rename)
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn history(api: &BoxedFilter<(impl Reply + 'static,)>, query: &str) -> Value {
+    let response = request("GET", &format!("/storage/theme/history{query}"))
+        .reply(api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body(&response)
+}
+
+#[tokio::test]
+async fn history_lists_the_revisions_most_recent_first() {
+    let api = api();
+    for theme in ["dark", "light"] {
+        request("PUT", "/storage/theme")
+            .json(&string(theme))
+            .reply(&api)
+            .await;
+    }
+    request("DELETE", "/storage/theme").reply(&api).await;
+
+    let page = history(&api, "?limit=2").await;
+    let revisions = page["revisions"].as_array().unwrap();
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0]["version"], 3);
+    assert_eq!(revisions[0]["entry"], Value::Null);
+    assert_eq!(revisions[1]["version"], 2);
+    assert_eq!(revisions[1]["entry"], string("light"));
+
+    let cursor = page["next_cursor"].as_str().unwrap();
+    let page = history(&api, &format!("?limit=2&after={cursor}")).await;
+    let revisions = page["revisions"].as_array().unwrap();
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0]["entry"], string("dark"));
+    assert!(page.get("next_cursor").is_none());
+}
+
+#[tokio::test]
+async fn restored_revision_becomes_the_next_version() {
+    let api = api();
+    for theme in ["dark", "light"] {
+        request("PUT", "/storage/theme")
+            .json(&string(theme))
+            .reply(&api)
+            .await;
+    }
+    let revisions = history(&api, "").await["revisions"].clone();
+    let first = revisions[1]["id"].clone();
+
+    let response = request("POST", "/storage/theme/restore")
+        .json(&json!({ "revision_id": first }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ETAG], "\"3\"");
+    assert_eq!(body(&response), string("dark"));
+
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("dark"));
+
+    // a deletion has no entry to restore
+    request("DELETE", "/storage/theme").reply(&api).await;
+    let deletion = history(&api, "?limit=1").await["revisions"][0]["id"].clone();
+    let response = request("POST", "/storage/theme/restore")
+        .json(&json!({ "revision_id": deletion }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = request("POST", "/storage/theme/restore")
+        .json(&json!({}))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn expired_revision_is_restored_with_a_new_expiry_only() {
+    let api = api();
+    request("PUT", "/storage/theme?expires_at=2020-01-01T00:00:00Z")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    let expired = history(&api, "").await["revisions"][0]["id"].clone();
+
+    let response = request("POST", "/storage/theme/restore")
+        .json(&json!({ "revision_id": expired }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body(&response)["errors"][0]["details"]["reason"],
+        "the revision has expired, a new ttl_seconds or expires_at is required"
+    );
+
+    let response = request("POST", "/storage/theme/restore")
+        .json(&json!({ "revision_id": expired, "ttl_seconds": 60 }))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), string("dark"));
+
+    let revisions = history(&api, "").await["revisions"].clone();
+    assert!(revisions[0]["expires_at"].is_string());
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("dark"));
+}
+
+#[tokio::test]
+async fn export_is_imported_by_another_user() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "theme", "entry": string("dark") },
+            { "key": "lang", "entry": string("en") },
+        ]),
+    )
+    .await;
+
+    let response = request("GET", "/storage/export?format=ndjson")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+    assert_eq!(response.body().split(|b| *b == b'\n').count(), 3);
+
+    let response = request("GET", "/storage/export").reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let exported = response.body().clone();
+
+    let import = |content_type: &str, body| {
+        warp::test::request()
+            .method("POST")
+            .path("/storage/import")
+            .header("X-User-Address", other_user_addr())
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+    };
+    let response = import("application/json", exported).reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(&response),
+        json!({ "created": 2, "updated": 0, "skipped": 0, "deleted": 0 })
+    );
+
+    let response = warp::test::request()
+        .path("/storage?keys%5B0%5D=theme&keys%5B1%5D=lang")
+        .header("X-User-Address", other_user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(
+        body(&response),
+        json!({ "entries": [string("dark"), string("en")] })
+    );
+}
+
+async fn import(
+    api: &BoxedFilter<(impl Reply + 'static,)>,
+    strategy: &str,
+    entries: Value,
+) -> Value {
+    let response = request("POST", &format!("/storage/import?strategy={strategy}"))
+        .json(&json!({ "entries": entries }))
+        .reply(api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body(&response)
+}
+
+#[tokio::test]
+async fn import_strategies_treat_the_existing_entries() {
+    let api = api();
+    put_entries(
+        &api,
+        json!([
+            { "key": "theme", "entry": string("dark") },
+            { "key": "lang", "entry": string("en") },
+        ]),
+    )
+    .await;
+
+    let summary = import(
+        &api,
+        "overwrite",
+        json!([
+            { "key": "theme", "entry": string("light") },
+            { "key": "font", "entry": string("mono") },
+        ]),
+    )
+    .await;
+    assert_eq!(
+        summary,
+        json!({ "created": 1, "updated": 1, "skipped": 0, "deleted": 0 })
+    );
+
+    let summary = import(
+        &api,
+        "skip_existing",
+        json!([
+            { "key": "theme", "entry": string("blue") },
+            { "key": "size", "entry": integer(12) },
+        ]),
+    )
+    .await;
+    assert_eq!(
+        summary,
+        json!({ "created": 1, "updated": 0, "skipped": 1, "deleted": 0 })
+    );
+    let response = request("GET", "/storage/theme").reply(&api).await;
+    assert_eq!(body(&response), string("light"));
+
+    let summary = import(
+        &api,
+        "replace_all",
+        json!([{ "key": "theme", "entry": string("green") }]),
+    )
+    .await;
+    assert_eq!(
+        summary,
+        json!({ "created": 0, "updated": 1, "skipped": 0, "deleted": 3 })
+    );
+    let response = request("GET", "/storage/keys").reply(&api).await;
+    assert_eq!(body(&response)["keys"], json!(["theme"]));
+}
+
+fn quota_api(quota: Quota) -> BoxedFilter<(impl Reply,)> {
+    let changes = ChangeFeed::new();
+    routes_with(
+        storage(changes.clone()),
+        changes,
+        &Config { quota, ..config() },
+    )
+}
+
+#[tokio::test]
+async fn writes_over_the_entry_quota_are_rejected() {
+    let api = quota_api(Quota {
+        max_entries: Some(2),
+        max_bytes: None,
+    });
+    put_entries(
+        &api,
+        json!([
+            { "key": "theme", "entry": string("dark") },
+            { "key": "lang", "entry": string("en") },
+        ]),
+    )
+    .await;
+
+    let response = request("PUT", "/storage/font")
+        .json(&string("mono"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let details = &body(&response)["errors"][0]["details"];
+    assert_eq!(details["parameter"], "font");
+    assert_eq!(details["max_entries"], "2");
+
+    // the entries within the quota can still be overwritten
+    let response = request("PUT", "/storage/theme")
+        .json(&string("light"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    request("DELETE", "/storage/lang").reply(&api).await;
+    let response = request("PUT", "/storage/font")
+        .json(&string("mono"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn entries_over_the_byte_quota_may_only_shrink() {
+    let api = quota_api(Quota {
+        max_entries: None,
+        max_bytes: Some(16),
+    });
+    request("PUT", "/storage/theme")
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+
+    let response = request("PUT", "/storage/theme")
+        .json(&string("a much darker one"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(body(&response)["errors"][0]["details"]["max_bytes"], "16");
+
+    let response = request("PUT", "/storage/theme")
+        .json(&string("dim"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+fn auth_api(auth: Auth) -> BoxedFilter<(impl Reply,)> {
+    let changes = ChangeFeed::new();
+    routes_with(
+        storage(changes.clone()),
+        changes,
+        &Config { auth, ..config() },
+    )
+}
+
+/// Signs requests the way Waves wallets do, with a Curve25519 key whose Ed25519 form
+/// carries its sign bit in the last byte of the signature.
+struct Wallet {
+    key: SigningKey,
+    public_key: [u8; waves::PUBLIC_KEY_LENGTH],
+    sign_bit: u8,
+}
+
+impl Wallet {
+    fn new(seed: u8) -> Self {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let edwards = key.verifying_key().to_bytes();
+        let public_key = CompressedEdwardsY(edwards)
+            .decompress()
+            .expect("valid public key")
+            .to_montgomery()
+            .to_bytes();
+        Wallet {
+            key,
+            public_key,
+            sign_bit: edwards[31] & 0x80,
+        }
+    }
+
+    fn user_addr(&self) -> String {
+        waves::address_from_public_key(&self.public_key, CHAIN_ID)
+    }
+
+    fn sign(&self, timestamp: i64, method: &str, target: &str, body: &str) -> String {
+        let content_type = if body.is_empty() {
+            ""
+        } else {
+            "application/json"
+        };
+        let message = format!("{timestamp}\n{method}\n{target}\n\n\n{content_type}\n{body}");
+        let mut signature = self.key.sign(message.as_bytes()).to_bytes();
+        signature[63] |= self.sign_bit;
+        bs58::encode(signature).into_string()
+    }
+
+    fn request_at(
+        &self,
+        timestamp: i64,
+        method: &str,
+        target: &str,
+        body: &str,
+    ) -> warp::test::RequestBuilder {
+        let request = warp::test::request()
+            .method(method)
+            .path(target)
+            .header("X-Public-Key", bs58::encode(self.public_key).into_string())
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Signature", self.sign(timestamp, method, target, body));
+        if body.is_empty() {
+            request
+        } else {
+            request.header(CONTENT_TYPE, "application/json").body(body)
+        }
+    }
+
+    fn request(&self, method: &str, target: &str, body: &str) -> warp::test::RequestBuilder {
+        self.request_at(Utc::now().timestamp_millis(), method, target, body)
+    }
+}
+
+fn signature_api() -> BoxedFilter<(impl Reply,)> {
+    auth_api(Auth::Signature {
+        max_age: Duration::from_secs(60),
+    })
+}
+
+#[tokio::test]
+async fn signed_requests_act_for_the_signer() {
+    let api = signature_api();
+    let wallet = Wallet::new(1);
+
+    let response = wallet
+        .request("PUT", "/storage/theme", &string("dark").to_string())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = wallet
+        .request("GET", "/storage/theme", "")
+        .header("X-User-Address", wallet.user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), string("dark"));
+
+    // the entries are the signer's only
+    let response = Wallet::new(2)
+        .request("GET", "/storage/theme", "")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = wallet
+        .request("GET", "/storage/theme", "")
+        .header("X-User-Address", Wallet::new(2).user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn tampered_stale_or_replayed_signatures_are_rejected() {
+    let api = signature_api();
+    let wallet = Wallet::new(3);
+
+    let response = wallet
+        .request("PUT", "/storage/theme", &string("dark").to_string())
+        .body(string("light").to_string())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let stale = Utc::now().timestamp_millis() - 120_000;
+    let response = wallet
+        .request_at(stale, "GET", "/storage/keys", "")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let now = Utc::now().timestamp_millis();
+    let response = wallet
+        .request_at(now, "GET", "/storage/keys", "")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = wallet
+        .request_at(now, "GET", "/storage/keys", "")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+const JWT_SECRET: &[u8] = b"test secret";
+
+fn jwt_api() -> BoxedFilter<(impl Reply,)> {
+    auth_api(Auth::Jwt(JwtConfig {
+        keys: vec![(None, DecodingKey::from_secret(JWT_SECRET))],
+        address_claim: "waves_address".to_string(),
+    }))
+}
+
+fn token(secret: &[u8], claims: Value) -> String {
+    let mut claims = claims;
+    claims["exp"] = json!(Utc::now().timestamp() + 60);
+    let token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+    .expect("encodable claims");
+    format!("Bearer {token}")
+}
+
+#[tokio::test]
+async fn bearer_token_acts_for_the_claimed_address() {
+    let api = jwt_api();
+    let authorization = token(JWT_SECRET, json!({ "waves_address": user_addr() }));
+
+    let response = warp::test::request()
+        .method("PUT")
+        .path("/storage/theme")
+        .header("Authorization", &authorization)
+        .json(&string("dark"))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = warp::test::request()
+        .path("/storage/theme")
+        .header("Authorization", &authorization)
+        .header("X-User-Address", user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(&response), string("dark"));
+
+    let response = warp::test::request()
+        .path("/storage/theme")
+        .header("Authorization", &authorization)
+        .header("X-User-Address", other_user_addr())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn tokens_of_unknown_keys_or_without_the_claim_are_rejected() {
+    let api = jwt_api();
+    let get = |authorization: String| {
+        warp::test::request()
+            .path("/storage/keys")
+            .header("Authorization", authorization)
+    };
+
+    let response = get(token(
+        b"other secret",
+        json!({ "waves_address": user_addr() }),
+    ))
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = warp::test::request()
+        .path("/storage/keys")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = get(token(JWT_SECRET, json!({ "sub": user_addr() })))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = get(token(
+        JWT_SECRET,
+        json!({ "waves_address": "not an address" }),
+    ))
+    .reply(&api)
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}